@@ -0,0 +1,103 @@
+//! Measures how retiring and reclaiming allocations scales with the amount of threads doing it at once,
+//! and how the cost of reclaiming replaced values changes after many threads read a keep and exited.
+//!
+//! Run with `cargo bench -p keep --bench reclamation`.
+
+use keep::*;
+use std::{
+    hint::black_box,
+    sync::{Arc, Barrier},
+    thread,
+    time::{Duration, Instant},
+};
+
+
+const THREADS: [usize; 5] = [1, 2, 4, 8, 16];
+const EXITED: [usize; 4] = [0, 16, 256, 4096];
+const ITERATIONS: usize = 200_000;
+
+
+/// Creates, reads and drops keeps on `threads` threads at once, which retires their tracked atomics.
+fn keep_churn(threads: usize) -> Duration
+{
+    let barrier = Arc::new(Barrier::new(threads + 1));
+
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                barrier.wait();
+
+                for i in 0..ITERATIONS / threads
+                {
+                    let keep = Keep::new(i);
+                    black_box(*keep.read());
+                }
+
+                barrier.wait();
+            })
+        })
+        .collect();
+
+    let start = Instant::now();
+    barrier.wait();
+    barrier.wait();
+    let elapsed = start.elapsed();
+
+    for handle in handles
+    {
+        handle.join().unwrap();
+    }
+
+    elapsed
+}
+
+
+/// Writes a keep while a guard of it is held, after `exited` threads read it and exited.
+///
+/// Every write retires the replaced value, so this measures whether exited threads slow reclamations down.
+fn writes_after_exits(exited: usize) -> Duration
+{
+    let keep = Arc::new(Keep::new(39usize));
+
+    for _ in 0..exited
+    {
+        let keep = keep.clone();
+        thread::spawn(move || black_box(*keep.read())).join().unwrap();
+    }
+
+    let held = keep.read();
+    let start = Instant::now();
+
+    for i in 0..ITERATIONS
+    {
+        keep.write(i);
+    }
+
+    let elapsed = start.elapsed();
+    drop(held);
+
+    elapsed
+}
+
+
+fn report(name: &str, unit: &str, sizes: &[usize], f: fn(usize) -> Duration)
+{
+    println!("{name}");
+
+    for &size in sizes
+    {
+        let elapsed = f(size);
+        let per_op = elapsed.as_nanos() as f64 / ITERATIONS as f64;
+
+        println!("  {size:>5} {unit}: {per_op:>8.1} ns per operation");
+    }
+}
+
+
+fn main()
+{
+    report("keeps created, read and dropped", "threads", &THREADS, keep_churn);
+    report("writes after threads exited", "exited threads", &EXITED, writes_after_exits);
+}
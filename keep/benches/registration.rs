@@ -0,0 +1,140 @@
+//! Measures how the cost of reading a keep and dropping the guard scales with the amount of live guards,
+//! also while a writer keeps replacing the value, so that dropped guards release retired values.
+//!
+//! Run with `cargo bench -p keep --bench registration`.
+
+use keep::*;
+use std::{
+    hint::black_box,
+    sync::{
+        Arc, Barrier,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+
+const READERS: [usize; 6] = [1, 4, 16, 64, 256, 1024];
+const ITERATIONS: usize = 200_000;
+
+
+/// Reads and drops a guard while `readers` other guards of the same keep are alive.
+fn held_guards(readers: usize) -> Duration
+{
+    let keep = Keep::new(39usize);
+    let held: Vec<_> = (0..readers).map(|_| keep.read()).collect();
+
+    let start = Instant::now();
+
+    for _ in 0..ITERATIONS
+    {
+        black_box(*keep.read());
+    }
+
+    let elapsed = start.elapsed();
+    drop(held);
+
+    elapsed
+}
+
+
+/// Reads and drops guards on `readers` threads at once, each holding a few more guards.
+fn concurrent_readers(readers: usize) -> Duration
+{
+    concurrent_readers_of(&Arc::new(Keep::new(39usize)), readers)
+}
+
+
+fn concurrent_readers_of(keep: &Arc<Keep<usize>>, readers: usize) -> Duration
+{
+    let barrier = Arc::new(Barrier::new(readers + 1));
+
+    let threads: Vec<_> = (0..readers)
+        .map(|_| {
+            let keep = keep.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                let held: Vec<_> = (0..4).map(|_| keep.read()).collect();
+                barrier.wait();
+
+                for _ in 0..ITERATIONS / readers
+                {
+                    black_box(*keep.read());
+                }
+
+                barrier.wait();
+                drop(held);
+            })
+        })
+        .collect();
+
+    // Every thread waits for this one, so no read can happen before the start
+    let start = Instant::now();
+    barrier.wait();
+
+    // Every thread did its share of the iterations, so this is the time per read of all of them
+    barrier.wait();
+    let elapsed = start.elapsed();
+
+    for thread in threads
+    {
+        thread.join().unwrap();
+    }
+
+    elapsed
+}
+
+
+/// Like `concurrent_readers(..)`, while another thread keeps writing new values.
+///
+/// Readers mostly drop guards of values that have been replaced in the meantime, which requests reclamations.
+fn concurrent_readers_and_writer(readers: usize) -> Duration
+{
+    let keep = Arc::new(Keep::new(39usize));
+    let done = Arc::new(AtomicBool::new(false));
+
+    let writer = {
+        let keep = keep.clone();
+        let done = done.clone();
+
+        thread::spawn(move || {
+            let mut value = 0;
+
+            while !done.load(Ordering::Relaxed)
+            {
+                value += 1;
+                keep.write(value);
+            }
+        })
+    };
+
+    let elapsed = concurrent_readers_of(&keep, readers);
+    done.store(true, Ordering::Relaxed);
+    writer.join().unwrap();
+
+    elapsed
+}
+
+
+fn report(name: &str, f: fn(usize) -> Duration)
+{
+    println!("{name}");
+
+    for readers in READERS
+    {
+        let elapsed = f(readers);
+        let per_op = elapsed.as_nanos() as f64 / ITERATIONS as f64;
+
+        println!("  {readers:>5} readers: {per_op:>8.1} ns per read and drop");
+    }
+}
+
+
+fn main()
+{
+    report("held guards", held_guards);
+    report("concurrent readers", concurrent_readers);
+    report("concurrent readers and a writer", concurrent_readers_and_writer);
+}
@@ -1,45 +1,171 @@
-use crate::tracked_atomic::GuardNode;
-use std::ops::Deref;
+use crate::tracked_atomic::{GuardNode, Record};
+use std::{mem, ops::Deref, sync::Arc};
 
 
-pub struct Guard<T>
+pub struct Guard<T: ?Sized>
 {
     guard_node: *mut GuardNode<T>,
+
+    /// The record of the guarded value, which is what the guard node is registered for.
+    record: *mut Record<T>,
     reference: *mut T,
 }
 
 
-impl<T> Guard<T>
+// A guard can be dropped on any thread, which might free the value, while others still read it.
+unsafe impl<T: ?Sized + Send + Sync> Send for Guard<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for Guard<T> {}
+
+
+impl<T: ?Sized> Guard<T>
 {
-    pub(crate) fn new(guard_node: *mut GuardNode<T>, reference: *mut T) -> Self
+    /// Creates a guard of the value of `record`, which must be guarded by `guard_node` already.
+    pub(crate) fn new(guard_node: *mut GuardNode<T>, record: *mut Record<T>) -> Self
     {
         Self {
             guard_node,
-            reference,
+            record,
+            reference: unsafe { &*record }.value().as_ptr(),
         }
     }
 
+    /// Returns the version of the guarded value, which counts the values stored in its tracked atomic before it.
+    ///
+    /// Values that are stored one after another have increasing versions, even if they share an address.
+    #[inline]
+    pub fn version(&self) -> u64
+    {
+        unsafe { &*self.record }.version()
+    }
+
+    /// Returns whether both guards guard the same stored value.
+    ///
+    /// Every store gets a record of its own, which lives as long as its value is guarded,
+    /// so a value stored at the address of another one, like every zero sized value, is never the same.
+    #[inline]
+    pub fn same_value(this: &Self, other: &Self) -> bool
+    {
+        this.record == other.record
+    }
+
     #[inline]
-    pub(crate) fn as_ptr(&self) -> *mut T
+    pub(crate) fn record(&self) -> *mut Record<T>
+    {
+        self.record
+    }
+
+    #[inline]
+    pub(crate) fn guard_node(&self) -> *mut GuardNode<T>
+    {
+        self.guard_node
+    }
+
+    /// Points this guard to the value of `record`.
+    ///
+    /// # Safety
+    /// The guard node of this guard must have been registered for `record` already.
+    #[inline]
+    pub(crate) unsafe fn set_record(&mut self, record: *mut Record<T>)
+    {
+        self.record = record;
+        self.reference = unsafe { &*record }.value().as_ptr();
+    }
+
+    /// Returns an arc of the guarded value, which can outlive this guard and the keep.
+    ///
+    /// Returns `None` if the value is not held by an arc, which it only is in keeps created by `Keep::from_arc(..)`.
+    pub fn to_arc(&self) -> Option<Arc<T>>
+    {
+        // The value cannot be freed while it is guarded
+        unsafe { (*self.record).value().to_arc() }
+    }
+
+    /// Projects the guard onto a part of its value, like one of its fields.
+    ///
+    /// The value stays guarded for as long as the returned guard lives.
+    /// This is an associated function, so that it does not shadow methods of `T` like `Option::map`.
+    pub fn map<U: ?Sized>(this: Self, f: impl FnOnce(&T) -> &U) -> MappedGuard<T, U>
+    {
+        let reference = f(unsafe { &*this.reference }) as *const U;
+
+        MappedGuard {
+            guard: this,
+            reference,
+        }
+    }
+
+    /// Projects the guard onto a part of its value, unless `f` fails.
+    ///
+    /// If `f` fails, the error is returned and the value is no longer guarded.
+    pub fn try_map<U: ?Sized, E>(
+        this: Self,
+        f: impl FnOnce(&T) -> Result<&U, E>,
+    ) -> Result<MappedGuard<T, U>, E>
+    {
+        let reference = f(unsafe { &*this.reference })? as *const U;
+
+        Ok(MappedGuard {
+            guard: this,
+            reference,
+        })
+    }
+
+    /// Projects the guard onto a part of its value, unless `f` returns `None`.
+    ///
+    /// If `f` returns `None`, the original guard is returned.
+    pub fn filter_map<U: ?Sized>(
+        this: Self,
+        f: impl FnOnce(&T) -> Option<&U>,
+    ) -> Result<MappedGuard<T, U>, Self>
+    {
+        match f(unsafe { &*this.reference })
+        {
+            Some(reference) => Ok(MappedGuard {
+                reference: reference as *const U,
+                guard: this,
+            }),
+
+            None => Err(this),
+        }
+    }
+}
+
+
+impl<T> Guard<T>
+{
+    /// Returns the value, if it has been replaced or its last keep was dropped
+    /// and this is the last guard of it. Values held by an arc must not be shared by any other arc either.
+    ///
+    /// Otherwise this guard is returned unchanged.
+    pub fn try_unwrap(self) -> Result<T, Self>
     {
-        self.reference
+        match unsafe { &*self.guard_node }.try_take(self.record)
+        {
+            true =>
+            {
+                let record = self.record;
+                mem::forget(self);
+                Ok(unsafe { Record::into_inner(record) })
+            }
+
+            false => Err(self),
+        }
     }
 }
 
 
-impl<T> Clone for Guard<T>
+impl<T: ?Sized> Clone for Guard<T>
 {
     fn clone(&self) -> Self
     {
-        Self {
-            guard_node: self.guard_node,
-            reference: self.reference,
-        }
+        // The value cannot be reclaimed while this guard registers the clone, as it is still guarded by this one.
+        let guard_node = unsafe { &*self.guard_node }.register_again(self.record);
+        Self::new(guard_node, self.record)
     }
 }
 
 
-impl<T> Deref for Guard<T>
+impl<T: ?Sized> Deref for Guard<T>
 {
     type Target = T;
 
@@ -50,7 +176,7 @@ impl<T> Deref for Guard<T>
 }
 
 
-impl<T> AsRef<T> for Guard<T>
+impl<T: ?Sized> AsRef<T> for Guard<T>
 {
     fn as_ref(&self) -> &T
     {
@@ -59,11 +185,11 @@ impl<T> AsRef<T> for Guard<T>
 }
 
 
-impl<T> Drop for Guard<T>
+impl<T: ?Sized> Drop for Guard<T>
 {
     fn drop(&mut self)
     {
-        unsafe { &*self.guard_node }.unregister(self.reference);
+        unsafe { &*self.guard_node }.unregister(self.record);
     }
 }
 
@@ -80,18 +206,98 @@ impl<T> Drop for Guard<T>
 // }
 
 
-impl<T: std::fmt::Debug> std::fmt::Debug for Guard<T>
+impl<T: ?Sized + std::fmt::Debug> std::fmt::Debug for Guard<T>
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
     {
         f.debug_struct("Guard")
-            .field("reference", self.as_ref())
+            .field("reference", &self.as_ref())
+            .finish()
+    }
+}
+
+
+impl<T: ?Sized + PartialEq> PartialEq for Guard<T>
+{
+    fn eq(&self, other: &Self) -> bool
+    {
+        self.as_ref() == other.as_ref()
+    }
+}
+
+
+impl<T: ?Sized + Eq> Eq for Guard<T> {}
+
+
+/// A guard projected onto a part of its value, created by `Guard::map(..)`.
+///
+/// It keeps the whole value guarded, while only giving access to the projected part.
+pub struct MappedGuard<T: ?Sized, U: ?Sized>
+{
+    guard: Guard<T>,
+    reference: *const U,
+}
+
+
+// The projected reference points into the guarded value, so it can be shared like the value itself.
+unsafe impl<T: ?Sized + Send + Sync, U: ?Sized + Sync> Send for MappedGuard<T, U> {}
+unsafe impl<T: ?Sized + Send + Sync, U: ?Sized + Sync> Sync for MappedGuard<T, U> {}
+
+
+impl<T: ?Sized, U: ?Sized> MappedGuard<T, U>
+{
+    /// Projects the guard further onto a part of its projected value, see `Guard::map(..)`.
+    pub fn map<V: ?Sized>(this: Self, f: impl FnOnce(&U) -> &V) -> MappedGuard<T, V>
+    {
+        let reference = f(unsafe { &*this.reference }) as *const V;
+
+        MappedGuard {
+            guard: this.guard,
+            reference,
+        }
+    }
+
+    /// Returns the guard of the whole value.
+    #[inline]
+    pub fn guard(this: &Self) -> &Guard<T>
+    {
+        &this.guard
+    }
+}
+
+
+impl<T: ?Sized, U: ?Sized> Deref for MappedGuard<T, U>
+{
+    type Target = U;
+
+    fn deref(&self) -> &U
+    {
+        unsafe { &*self.reference }
+    }
+}
+
+
+impl<T: ?Sized, U: ?Sized> AsRef<U> for MappedGuard<T, U>
+{
+    fn as_ref(&self) -> &U
+    {
+        unsafe { &*self.reference }
+    }
+}
+
+
+impl<T: ?Sized, U: ?Sized + std::fmt::Debug> std::fmt::Debug for MappedGuard<T, U>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        f.debug_struct("MappedGuard")
+            .field("reference", &self.as_ref())
             .finish()
     }
 }
 
 
-impl<T: PartialEq> PartialEq for Guard<T>
+impl<T: ?Sized, U: ?Sized + PartialEq> PartialEq for MappedGuard<T, U>
 {
     fn eq(&self, other: &Self) -> bool
     {
@@ -100,4 +306,4 @@ impl<T: PartialEq> PartialEq for Guard<T>
 }
 
 
-impl<T: Eq> Eq for Guard<T> {}
+impl<T: ?Sized, U: ?Sized + Eq> Eq for MappedGuard<T, U> {}
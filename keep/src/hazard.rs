@@ -0,0 +1,508 @@
+use std::{
+    cell::RefCell,
+    marker::PhantomData,
+    mem::{self, ManuallyDrop},
+    ops::Deref,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+
+
+/// Amount of allocations a thread retires at least, before it scans for reclaimable ones.
+///
+/// Scans walk every slot, so threads also wait for twice as many retired allocations as there are slots,
+/// which keeps the average cost of a retirement constant.
+const RECLAIM_THRESHOLD: usize = 64;
+
+/// Amount of released slots a thread keeps for its next protections.
+///
+/// Protections are rarely nested deeper. Further slots are unlinked once they are released
+/// and so are the cached ones once their thread exits, so that the slot list shrinks again.
+const CACHED_SLOTS: usize = 4;
+
+
+static SLOTS: AtomicPtr<Slot> = AtomicPtr::new(ptr::null_mut());
+
+/// The amount of slots in the slot list.
+static SLOT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// The amount of threads walking the slot list, unlinked slots are only freed while nobody walks it.
+static WALKERS: AtomicUsize = AtomicUsize::new(0);
+
+/// The top of the stack of slots that have been unlinked, but might still be walked.
+static UNLINKED: AtomicPtr<Slot> = AtomicPtr::new(ptr::null_mut());
+
+/// Set while a thread unlinks a slot, which no other thread ever waits for.
+static UNLINKING: AtomicBool = AtomicBool::new(false);
+
+/// The top of the stack of retired allocations that exited threads could not free yet.
+static ORPHANS: AtomicPtr<Orphans> = AtomicPtr::new(ptr::null_mut());
+
+
+/// A slot announcing that a thread is currently using the allocation it points to.
+///
+/// Slots are reused by other protections once they are released, unless they are unlinked from the slot list.
+struct Slot
+{
+    active: AtomicBool,
+    ptr: AtomicPtr<()>,
+    next: AtomicPtr<Slot>,
+
+    /// The next slot on the stack of unlinked slots, once this one is unlinked.
+    next_unlinked: AtomicPtr<Slot>,
+}
+
+
+/// The slots and retired allocations of a thread.
+struct Local
+{
+    /// Released slots kept for the next protections of this thread, which stay active while they are cached.
+    slots: RefCell<Vec<&'static Slot>>,
+
+    /// Allocations retired by this thread, that could not be freed yet.
+    retired: RefCell<Vec<Retired>>,
+}
+
+
+impl Drop for Local
+{
+    fn drop(&mut self)
+    {
+        for slot in self.slots.get_mut().drain(..)
+        {
+            slot.unlink();
+        }
+
+        // Allocations that are still protected are left to the next thread that scans
+        let retired = mem::take(self.retired.get_mut());
+        orphan(reclaim(retired));
+    }
+}
+
+
+thread_local! {
+    static LOCAL: Local = const {
+        Local {
+            slots: RefCell::new(Vec::new()),
+            retired: RefCell::new(Vec::new()),
+        }
+    };
+}
+
+
+impl Slot
+{
+    /// Acquires a slot cached by this thread, a free slot or links a new one into the slot list.
+    fn acquire() -> &'static Slot
+    {
+        if let Some(slot) = LOCAL.try_with(|local| local.slots.borrow_mut().pop()).ok().flatten()
+        {
+            return slot;
+        }
+
+        let free = walk(|mut slots| {
+            slots.find(|slot| {
+                !slot.active.load(Ordering::Relaxed)
+                    && slot
+                        .active
+                        .compare_exchange(false, true, Ordering::SeqCst, Ordering::Relaxed)
+                        .is_ok()
+            })
+        });
+
+        if let Some(slot) = free
+        {
+            return slot;
+        }
+
+        let slot: &'static Slot = Box::leak(Box::new(Slot {
+            active: AtomicBool::new(true),
+            ptr: AtomicPtr::new(ptr::null_mut()),
+            next: AtomicPtr::new(SLOTS.load(Ordering::SeqCst)),
+            next_unlinked: AtomicPtr::new(ptr::null_mut()),
+        }));
+
+        SLOT_COUNT.fetch_add(1, Ordering::Relaxed);
+
+        loop
+        {
+            let next = slot.next.load(Ordering::SeqCst);
+
+            match SLOTS.compare_exchange(next, slot as *const _ as *mut _, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => break slot,
+                Err(actual) => slot.next.store(actual, Ordering::SeqCst),
+            }
+        }
+    }
+
+    fn release(&'static self)
+    {
+        self.ptr.store(ptr::null_mut(), Ordering::SeqCst);
+
+        // Keep the slot for the next protection of this thread, unless it already keeps enough of them
+        let cached = LOCAL
+            .try_with(|local| {
+                let mut slots = local.slots.borrow_mut();
+                let room = slots.len() < CACHED_SLOTS;
+
+                if room
+                {
+                    slots.push(self);
+                }
+
+                room
+            })
+            .unwrap_or(false);
+
+        if !cached
+        {
+            self.unlink();
+        }
+    }
+
+    /// Unlinks this slot from the slot list and frees it, once no walk can reach it anymore.
+    ///
+    /// If another thread is unlinking a slot already, this one is released for reuse instead.
+    fn unlink(&'static self)
+    {
+        if UNLINKING.swap(true, Ordering::SeqCst)
+        {
+            self.active.store(false, Ordering::Release);
+            return;
+        }
+
+        let this = self as *const Slot as *mut Slot;
+
+        walk(|_| {
+            // Only the unlinking thread changes the links behind the first slot, other threads only link new slots
+            // in front of it. So a link that does not point to this slot points to a slot before it.
+            let mut link = &SLOTS;
+
+            while let Err(actual) =
+                link.compare_exchange(this, self.next.load(Ordering::SeqCst), Ordering::SeqCst, Ordering::SeqCst)
+            {
+                link = &unsafe { &*actual }.next;
+            }
+
+            SLOT_COUNT.fetch_sub(1, Ordering::Relaxed);
+            push_unlinked(this, this);
+        });
+
+        UNLINKING.store(false, Ordering::SeqCst);
+    }
+}
+
+
+/// The slots of the slot list, as walked by `walk(..)`.
+struct Slots
+{
+    next: *mut Slot,
+}
+
+
+impl Iterator for Slots
+{
+    type Item = &'static Slot;
+
+    fn next(&mut self) -> Option<&'static Slot>
+    {
+        let slot = unsafe { self.next.as_ref() }?;
+        self.next = slot.next.load(Ordering::SeqCst);
+
+        Some(slot)
+    }
+}
+
+
+/// Calls `f` with the slots of the slot list, which are not freed before `f` returns, even if they are unlinked.
+///
+/// Slots that are not owned by the caller must not be used after `f` returned.
+fn walk<R>(f: impl FnOnce(Slots) -> R) -> R
+{
+    WALKERS.fetch_add(1, Ordering::SeqCst);
+
+    let result = f(Slots {
+        next: SLOTS.load(Ordering::SeqCst),
+    });
+
+    // Unlinked slots can only be reached by walks that started before they were unlinked.
+    // So if nobody else walks once the unlinked slots have been taken, no walk can reach them anymore.
+    let mut unlinked = UNLINKED.swap(ptr::null_mut(), Ordering::SeqCst);
+
+    if WALKERS.fetch_sub(1, Ordering::SeqCst) == 1
+    {
+        while !unlinked.is_null()
+        {
+            let slot = unsafe { Box::from_raw(unlinked) };
+            unlinked = slot.next_unlinked.load(Ordering::SeqCst);
+        }
+    }
+    else if !unlinked.is_null()
+    {
+        let mut last = unlinked;
+
+        while let Some(next) = unsafe { (*last).next_unlinked.load(Ordering::SeqCst).as_mut() }
+        {
+            last = next;
+        }
+
+        push_unlinked(unlinked, last);
+    }
+
+    result
+}
+
+
+/// Pushes the unlinked slots from `first` to `last` onto the stack of unlinked slots.
+fn push_unlinked(first: *mut Slot, last: *mut Slot)
+{
+    let mut top = UNLINKED.load(Ordering::SeqCst);
+
+    loop
+    {
+        unsafe { &*last }.next_unlinked.store(top, Ordering::SeqCst);
+
+        match UNLINKED.compare_exchange(top, first, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Ok(_) => break,
+            Err(actual) => top = actual,
+        }
+    }
+}
+
+
+/// An allocation that is no longer reachable, but may still be in use by protected threads.
+struct Retired
+{
+    ptr: *mut (),
+    free: unsafe fn(*mut ()),
+}
+
+
+// Retired allocations are only ever freed by a single thread after they became unreachable.
+unsafe impl Send for Retired {}
+
+
+/// Retired allocations of a thread that exited before they could be freed.
+struct Orphans
+{
+    retired: Vec<Retired>,
+    next: *mut Orphans,
+}
+
+
+/// A reference to an allocation that cannot be freed while this protection is alive.
+pub(crate) struct Protected<'a, T: ?Sized>
+{
+    slot: &'static Slot,
+    ptr: *mut T,
+    _atomic: PhantomData<&'a T>,
+}
+
+
+impl<'a, T: ?Sized> Protected<'a, T>
+{
+    #[inline]
+    pub fn as_ptr(&self) -> *mut T
+    {
+        self.ptr
+    }
+
+    /// Returns the address announced by this protection, which stays the same when it is mapped.
+    #[inline]
+    pub fn announced(&self) -> *mut ()
+    {
+        self.slot.ptr.load(Ordering::Relaxed)
+    }
+
+    /// Points the protection to a part of the protected allocation, which stays announced.
+    pub fn map<U: ?Sized>(self, f: impl FnOnce(&T) -> *mut U) -> Protected<'a, U>
+    {
+        let this = ManuallyDrop::new(self);
+
+        Protected {
+            slot: this.slot,
+            ptr: f(unsafe { &*this.ptr }),
+            _atomic: PhantomData,
+        }
+    }
+}
+
+
+impl<T: ?Sized> Deref for Protected<'_, T>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T
+    {
+        unsafe { &*self.ptr }
+    }
+}
+
+
+impl<T: ?Sized> Drop for Protected<'_, T>
+{
+    fn drop(&mut self)
+    {
+        self.slot.release();
+    }
+}
+
+
+/// Loads `atomic` and protects the loaded allocation from being freed by `retire(..)`.
+///
+/// `atomic` must only ever contain pointers which are retired using `retire(..)`
+/// after they have been removed from every atomic that could contain them.
+/// If `atomic` may be null, the protection must be checked using `Protected::as_ptr()` before it is dereferenced.
+pub(crate) fn protect<T>(atomic: &AtomicPtr<T>) -> Protected<'_, T>
+{
+    let slot = Slot::acquire();
+    let mut ptr = atomic.load(Ordering::SeqCst);
+
+    loop
+    {
+        slot.ptr.store(ptr as *mut (), Ordering::SeqCst);
+
+        // If the atomic still holds ptr after announcing it, it was not retired yet
+        // and any reclamation will observe the announcement.
+        match atomic.load(Ordering::SeqCst)
+        {
+            actual if actual == ptr => break,
+            actual => ptr = actual,
+        }
+    }
+
+    Protected {
+        slot,
+        ptr,
+        _atomic: PhantomData,
+    }
+}
+
+
+/// Announces that `ptr` is in use, which only protects it if it has not been retired before.
+///
+/// The caller has to check that `ptr` is still reachable after this returned, before it is dereferenced.
+pub(crate) fn announce<'a, T: ?Sized>(ptr: *mut T) -> Protected<'a, T>
+{
+    let slot = Slot::acquire();
+    slot.ptr.store(ptr.cast(), Ordering::SeqCst);
+
+    Protected {
+        slot,
+        ptr,
+        _atomic: PhantomData,
+    }
+}
+
+
+/// Returns every pointer that is currently protected.
+pub(crate) fn protected() -> Vec<*mut ()>
+{
+    walk(|slots| {
+        slots
+            .map(|slot| slot.ptr.load(Ordering::SeqCst))
+            .filter(|ptr| !ptr.is_null())
+            .collect()
+    })
+}
+
+
+/// Returns whether `ptr` is currently protected.
+pub(crate) fn is_protected(ptr: *mut ()) -> bool
+{
+    walk(|mut slots| slots.any(|slot| slot.ptr.load(Ordering::SeqCst) == ptr))
+}
+
+
+/// Frees `ptr` using `free` as soon as no protection is referencing it anymore.
+///
+/// Every thread collects its own retired allocations, until there are enough of them to scan the slots.
+///
+/// # Safety
+/// `ptr` must be unreachable for any new protections and `free` must be safe to call with it.
+pub(crate) unsafe fn retire(ptr: *mut (), free: unsafe fn(*mut ()))
+{
+    let mut retired = Some(Retired { ptr, free });
+
+    let full = LOCAL.try_with(|local| {
+        let mut local_retired = local.retired.borrow_mut();
+        local_retired.extend(retired.take());
+
+        local_retired.len() >= RECLAIM_THRESHOLD.max(2 * SLOT_COUNT.load(Ordering::Relaxed))
+    });
+
+    match full
+    {
+        Ok(true) =>
+        {
+            let retired = LOCAL.with(|local| mem::take(&mut *local.retired.borrow_mut()));
+            let still_protected = reclaim(retired);
+            LOCAL.with(|local| local.retired.borrow_mut().extend(still_protected));
+        }
+
+        Ok(false) => {}
+
+        // Threads that are exiting cannot collect anymore
+        Err(_) => orphan(retired.into_iter().collect()),
+    }
+}
+
+
+/// Frees every allocation of `retired`, or left by exited threads, that is not protected and returns the others.
+fn reclaim(mut retired: Vec<Retired>) -> Vec<Retired>
+{
+    let mut orphans = ORPHANS.swap(ptr::null_mut(), Ordering::SeqCst);
+
+    while !orphans.is_null()
+    {
+        let adopted = unsafe { Box::from_raw(orphans) };
+        retired.extend(adopted.retired);
+        orphans = adopted.next;
+    }
+
+    let mut protected = protected();
+    protected.sort_unstable();
+
+    let (reclaimable, still_protected): (Vec<_>, Vec<_>) = retired
+        .into_iter()
+        .partition(|r| protected.binary_search(&r.ptr).is_err());
+
+    // Freeing may retire other allocations, which are collected as usual
+    for r in reclaimable
+    {
+        unsafe { (r.free)(r.ptr) };
+    }
+
+    still_protected
+}
+
+
+/// Leaves `retired` to the next thread that scans.
+fn orphan(retired: Vec<Retired>)
+{
+    if retired.is_empty()
+    {
+        return;
+    }
+
+    let orphans = Box::into_raw(Box::new(Orphans {
+        retired,
+        next: ORPHANS.load(Ordering::SeqCst),
+    }));
+
+    loop
+    {
+        match ORPHANS.compare_exchange(
+            unsafe { (*orphans).next },
+            orphans,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        )
+        {
+            Ok(_) => break,
+            Err(actual) => unsafe { (*orphans).next = actual },
+        }
+    }
+}
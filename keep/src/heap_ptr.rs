@@ -1,53 +1,168 @@
-pub struct HeapPtr<T>(*mut T);
-impl<T> HeapPtr<T>
+use std::{borrow::Cow, mem::ManuallyDrop, sync::Arc};
+
+
+pub struct HeapPtr<T: ?Sized>
+{
+    ptr: *mut T,
+    holder: Holder,
+}
+
+
+/// The allocation that holds the value of a `HeapPtr`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Holder
+{
+    Box,
+    Arc,
+
+    /// The value has been moved into the allocation of its record, which frees it, see `Record::new(..)`.
+    Record,
+}
+
+
+impl<T: ?Sized> HeapPtr<T>
 {
     #[inline]
     pub fn as_ptr(&self) -> *mut T
     {
-        self.0
+        self.ptr
+    }
+
+    /// Returns whether the value is held by an `Arc`, which can be shared with code outside of keeps.
+    #[inline]
+    pub(crate) fn is_arc(&self) -> bool
+    {
+        self.holder == Holder::Arc
+    }
+
+    #[inline]
+    pub(crate) fn holder(&self) -> Holder
+    {
+        self.holder
     }
 
-    /// Frees the memory this `HeapPtr` is pointing at.
+    /// Frees the memory this `HeapPtr` is pointing at, or releases its arc.
     ///
     /// # Safety
     /// The caller is responsible for avoiding use after free errors.
     #[inline]
     pub(crate) unsafe fn free(self)
     {
-        drop(unsafe { Box::from_raw(self.0) })
+        match self.holder
+        {
+            Holder::Box => drop(unsafe { Box::from_raw(self.ptr) }),
+            Holder::Arc => drop(unsafe { Arc::from_raw(self.ptr) }),
+            Holder::Record => unreachable!("values inside a record are freed by `Record::free(..)`"),
+        }
     }
 
     #[inline]
-    pub(crate) fn from_ptr(ptr: *mut T) -> Self
+    pub(crate) fn from_ptr(ptr: *mut T, holder: Holder) -> Self
+    {
+        Self { ptr, holder }
+    }
+
+    /// Takes over the value of `arc` without copying it.
+    #[inline]
+    pub(crate) fn from_arc(arc: Arc<T>) -> Self
+    {
+        Self::from_ptr(Arc::into_raw(arc) as *mut T, Holder::Arc)
+    }
+
+    /// Moves a boxed value into an arc, so that it can be shared by `Guard::to_arc()`.
+    ///
+    /// # Safety
+    /// Nobody else may reference the value yet.
+    pub(crate) unsafe fn into_arc(self) -> Self
     {
-        Self(ptr)
+        match self.holder
+        {
+            Holder::Box => Self::from_arc(Arc::from(unsafe { Box::from_raw(self.ptr) })),
+            Holder::Arc => self,
+            Holder::Record => unreachable!("values are moved into arcs before their record is allocated"),
+        }
+    }
+
+    /// Returns a new arc of the value, if it is held by an arc.
+    ///
+    /// # Safety
+    /// The value must not be freed while this is running.
+    pub(crate) unsafe fn to_arc(self) -> Option<Arc<T>>
+    {
+        self.is_arc().then(|| unsafe {
+            Arc::increment_strong_count(self.ptr);
+            Arc::from_raw(self.ptr)
+        })
+    }
+
+    /// Returns whether nothing but this `HeapPtr` references the value, which might be shared by other arcs.
+    ///
+    /// # Safety
+    /// The value must not be freed while this is running.
+    pub(crate) unsafe fn is_unique(&self) -> bool
+    {
+        if !self.is_arc()
+        {
+            return true;
+        }
+
+        // Weak arcs of the value could be upgraded at any time, so they count as references as well
+        let mut arc = ManuallyDrop::new(unsafe { Arc::from_raw(self.ptr) });
+        Arc::get_mut(&mut arc).is_some()
+    }
+}
+
+
+impl<T> HeapPtr<T>
+{
+    /// Moves the value out of its allocation and frees it.
+    ///
+    /// # Safety
+    /// Nothing else may reference the value anymore, see `HeapPtr::is_unique()`.
+    pub(crate) unsafe fn into_inner(self) -> T
+    {
+        match self.holder
+        {
+            Holder::Box => *unsafe { Box::from_raw(self.ptr) },
+            Holder::Arc => Arc::into_inner(unsafe { Arc::from_raw(self.ptr) }).expect("the arc is unique"),
+            Holder::Record => unreachable!("values inside a record are taken by `Record::into_inner(..)`"),
+        }
     }
 }
 
 
-impl<T> AsRef<T> for HeapPtr<T>
+impl<T: ?Sized> AsRef<T> for HeapPtr<T>
 {
     fn as_ref(&self) -> &T
     {
-        unsafe { &*self.0 }
+        unsafe { &*self.ptr }
     }
 }
 
 
-impl<T> Copy for HeapPtr<T> {}
+impl<T: ?Sized> Copy for HeapPtr<T> {}
 #[allow(clippy::non_canonical_clone_impl)]
-impl<T> Clone for HeapPtr<T>
+impl<T: ?Sized> Clone for HeapPtr<T>
 {
     fn clone(&self) -> Self
     {
-        Self(self.0)
+        Self {
+            ptr: self.ptr,
+            holder: self.holder,
+        }
     }
 }
 
 
+/// Values that can be moved into a keep, without allocating them again if they already are on the heap.
+///
+/// Unsized values like `str`, `[T]` or `dyn Trait` can be moved in from their boxes, or from `String` and `Vec<T>`.
+/// Arcs are kept like any other value, keeps that share their values with arcs are created by `Keep::from_arc(..)`.
+///
 /// # Safety
-/// The resulting `HeapPtr<T>` must point to non-null, aligned and heap allocated `T`.
-pub unsafe trait Heaped<T>
+/// The resulting `HeapPtr<T>` must point to non-null, aligned and heap allocated `T`,
+/// that can be freed as a `Box<T>`, or as an `Arc<T>` if it is held by an arc.
+pub unsafe trait Heaped<T: ?Sized>
 {
     fn heap_ptr(self) -> HeapPtr<T>;
 }
@@ -63,17 +178,17 @@ unsafe impl<T> Heaped<T> for T
 }
 
 
-unsafe impl<T> Heaped<T> for Box<T>
+unsafe impl<T: ?Sized> Heaped<T> for Box<T>
 {
     #[inline]
     fn heap_ptr(self) -> HeapPtr<T>
     {
-        HeapPtr(Box::into_raw(self))
+        HeapPtr::from_ptr(Box::into_raw(self), Holder::Box)
     }
 }
 
 
-unsafe impl<T> Heaped<T> for HeapPtr<T>
+unsafe impl<T: ?Sized> Heaped<T> for HeapPtr<T>
 {
     #[inline]
     fn heap_ptr(self) -> HeapPtr<T>
@@ -81,3 +196,36 @@ unsafe impl<T> Heaped<T> for HeapPtr<T>
         self
     }
 }
+
+
+unsafe impl<T> Heaped<T> for Cow<'_, T>
+where
+    T: ?Sized + ToOwned,
+    T::Owned: Heaped<T>,
+{
+    #[inline]
+    fn heap_ptr(self) -> HeapPtr<T>
+    {
+        self.into_owned().heap_ptr()
+    }
+}
+
+
+unsafe impl Heaped<str> for String
+{
+    #[inline]
+    fn heap_ptr(self) -> HeapPtr<str>
+    {
+        self.into_boxed_str().heap_ptr()
+    }
+}
+
+
+unsafe impl<T> Heaped<[T]> for Vec<T>
+{
+    #[inline]
+    fn heap_ptr(self) -> HeapPtr<[T]>
+    {
+        self.into_boxed_slice().heap_ptr()
+    }
+}
@@ -1,10 +1,38 @@
-use crate::{Guard, Heaped, atomic_swap, tracked_atomic::TrackedAtomic};
-use std::sync::atomic::{AtomicPtr, Ordering};
+use crate::{
+    Guard, HeapPtr, Heaped,
+    hazard::{self, Protected},
+    mcas::{self, Anchor, Word},
+    pinned::Pinned,
+    tracked_atomic::{Reclaimer, Record, TrackedAtomic},
+    watcher::{Changed, Watcher},
+    weak_keep::WeakKeep,
+};
+use std::{
+    mem::{self, MaybeUninit},
+    ptr,
+    sync::{
+        Arc,
+        atomic::{AtomicPtr, AtomicUsize, Ordering},
+    },
+    task::Waker,
+    time::{Duration, Instant},
+};
 
 
-pub struct KeepMarker<T>(*mut TrackedAtomic<T>);
-impl<T> Copy for KeepMarker<T> {}
-impl<T> Clone for KeepMarker<T>
+/// Marks the tracked atomic a keep references, see `Keep::mark()`.
+pub struct KeepMarker<T: ?Sized>
+{
+    tracked_atomic: *mut TrackedAtomic<T>,
+
+    /// The id of the tracked atomic, in case another one is allocated at its address later.
+    id: u64,
+}
+
+
+unsafe impl<T: ?Sized + Send + Sync> Send for KeepMarker<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for KeepMarker<T> {}
+impl<T: ?Sized> Copy for KeepMarker<T> {}
+impl<T: ?Sized> Clone for KeepMarker<T>
 {
     fn clone(&self) -> Self
     {
@@ -13,17 +41,55 @@ impl<T> Clone for KeepMarker<T>
 }
 
 
-pub struct Keep<T>
+/// Tags the cell of a keep in the lowest bit, once it has been moved into one.
+const CELL: usize = 1;
+
+
+pub struct Keep<T: ?Sized>
 {
+    /// The tracked atomic of this keep, or its cell once it has been swapped, see `Cell`.
     tracked_atomic: AtomicPtr<TrackedAtomic<T>>,
 }
 
 
-impl<T> Keep<T>
+// Values are shared between every thread holding a keep or guard
+// and are dropped by whichever thread releases the last of them.
+unsafe impl<T: ?Sized + Send + Sync> Send for Keep<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for Keep<T> {}
+
+
+impl<T: ?Sized> Keep<T>
 {
     pub fn new(value: impl Heaped<T>) -> Self
     {
-        let tracked_atomic = TrackedAtomic::new(value).heap_ptr();
+        Self::with_values(value, false, None)
+    }
+
+    /// Creates a keep that holds every value in an `Arc`, starting with `value`, without copying it.
+    ///
+    /// Values stored later are moved into arcs as well, so `Guard::to_arc()` always succeeds
+    /// and guarded values can be handed to code that does not know about keeps.
+    pub fn from_arc(value: Arc<T>) -> Self
+    {
+        Self::with_values(HeapPtr::from_arc(value), true, None)
+    }
+
+    /// Creates a keep holding `value`, that hands its values to `reclaimer` once they are no longer referenced,
+    /// instead of dropping them on whichever thread releases them last. This way they can be returned to a pool.
+    ///
+    /// The reclaimer runs on the releasing thread. Values taken by `try_unwrap()` are never reclaimed.
+    /// Values that were never stored, like the rejected value of a failed exchange, are reclaimed right away.
+    /// Replaced values are reclaimed in batches while other guards are around, so they might reach the reclaimer
+    /// some time after their last guard is dropped. Once at most one guard is left, they are reclaimed right away.
+    pub fn with_reclaimer(value: impl Heaped<T>, reclaimer: impl Fn(Box<T>) + Send + Sync + 'static) -> Self
+    {
+        Self::with_values(value, false, Some(Box::new(reclaimer)))
+    }
+
+    /// Creates a keep of a new tracked atomic holding `value`, that moves all of its values into arcs if `arcs` is set.
+    fn with_values(value: impl Heaped<T>, arcs: bool, reclaimer: Option<Reclaimer<T>>) -> Self
+    {
+        let tracked_atomic = TrackedAtomic::new(value, arcs, reclaimer);
         tracked_atomic.as_ref().register_keep();
 
         Self {
@@ -31,49 +97,146 @@ impl<T> Keep<T>
         }
     }
 
+    /// Creates a keep of `tracked_atomic`, which has already been registered for it.
+    #[inline]
+    pub(crate) fn from_registered(tracked_atomic: *mut TrackedAtomic<T>) -> Self
+    {
+        Self {
+            tracked_atomic: AtomicPtr::new(tracked_atomic),
+        }
+    }
+
+    /// Creates a weak keep of the current tracked atomic of this keep.
+    ///
+    /// The weak keep does not keep the value alive, it can only be upgraded as long as any keep of it is left.
+    pub fn downgrade(&self) -> WeakKeep<T>
+    {
+        self.with_tracked(|tracked| {
+            tracked
+                .try_register_weak()
+                .then(|| WeakKeep::from_registered(tracked as *const _ as *mut _))
+        })
+    }
+
     /// Swaps the referenced tracked atomic of two keeps.
     ///
+    /// Both keeps change at once, no thread can observe one of them swapped and the other one not.
     /// If you need to swap the values of two keeps use `Keep::swap_with(..)`,
     /// if you want to swap the value a keep use `Keep::swap(..)` instead.
     pub fn swap_with(&self, other: &Self)
     {
-        atomic_swap(&self.tracked_atomic, &other.tracked_atomic);
+        let (cell, other_cell) = (self.cell(), other.cell());
+
+        if ptr::eq(cell, other_cell)
+        {
+            return;
+        }
+
+        loop
+        {
+            let tracked_atomic = mcas::load(&cell.tracked_atomic);
+            let other_tracked_atomic = mcas::load(&other_cell.tracked_atomic);
+
+            if Cell::swap(cell, tracked_atomic, other_cell, other_tracked_atomic)
+            {
+                break;
+            }
+        }
     }
 
     pub fn mark(&self) -> KeepMarker<T>
     {
-        KeepMarker(self.tracked_atomic.load(Ordering::SeqCst))
+        let tracked_atomic = self.load();
+
+        KeepMarker {
+            tracked_atomic: tracked_atomic.as_ptr(),
+            id: tracked_atomic.id(),
+        }
     }
 
+    /// Swaps the referenced tracked atomic of two keeps like `Keep::swap_with(..)`,
+    /// if this keep still references the tracked atomic marked by `current`.
+    ///
+    /// Fails with a marker of the actual tracked atomic of this keep otherwise,
+    /// even if a new tracked atomic happens to be allocated at the address of the marked one.
     pub fn exchange_with(&self, current: KeepMarker<T>, other: &Self) -> Result<(), KeepMarker<T>>
     {
-        match self.tracked_atomic.compare_exchange(
-            current.0,
-            other.tracked_atomic.load(Ordering::SeqCst),
-            Ordering::SeqCst,
-            Ordering::SeqCst,
-        )
+        loop
         {
-            Ok(_) =>
+            // The protection keeps the address from being reused until the exchange is done
+            let tracked_atomic = self.load();
+            let ptr = tracked_atomic.as_ptr();
+
+            if ptr != current.tracked_atomic || tracked_atomic.id() != current.id
+            {
+                return Err(KeepMarker {
+                    tracked_atomic: ptr,
+                    id: tracked_atomic.id(),
+                });
+            }
+
+            let (cell, other_cell) = (self.cell(), other.cell());
+
+            if ptr::eq(cell, other_cell)
             {
-                other.tracked_atomic.store(current.0, Ordering::SeqCst);
-                Ok(())
+                return Ok(());
             }
 
-            Err(actual) => Err(KeepMarker(actual)),
+            let other_tracked_atomic = mcas::load(&other_cell.tracked_atomic);
+
+            if Cell::swap(cell, ptr, other_cell, other_tracked_atomic)
+            {
+                return Ok(());
+            }
         }
     }
 
     /// Reads the current value from this keep's tracked atomic
     pub fn read(&self) -> Guard<T>
     {
-        self.load().read()
+        self.with_tracked(|tracked| tracked.read())
+    }
+
+    /// Calls `f` with the current value, without registering a guard for it.
+    ///
+    /// The value cannot be reclaimed while `f` is running, but unlike a guard it cannot outlive `f`.
+    /// This is cheaper than `Keep::read()` for short reads.
+    pub fn read_with<R>(&self, f: impl FnOnce(&T) -> R) -> R
+    {
+        loop
+        {
+            let tracked_atomic = self.load();
+
+            if let Some(value) = tracked_atomic.protect()
+            {
+                let result = f(&value);
+                tracked_atomic.unprotect(value);
+                break result;
+            }
+        }
+    }
+
+    /// Pins the current value, so that it can be read many times while registering a guard only once.
+    ///
+    /// See `Pinned::refresh(..)` to move on to the newest value.
+    pub fn pin(&self) -> Pinned<'_, T>
+    {
+        Pinned::new(self, self.read())
+    }
+
+    /// Points `guard` to the current value, reusing its registration if possible.
+    ///
+    /// Returns whether the value changed.
+    pub(crate) fn refresh(&self, guard: &mut Guard<T>) -> bool
+    {
+        self.with_tracked(|tracked| tracked.refresh(guard))
     }
 
     /// Stores a new value in this keep's tracked atomic
     pub fn write(&self, value: impl Heaped<T>)
     {
-        self.load().write(value)
+        let mut value = value.heap_ptr();
+        self.with_tracked(|tracked| tracked.write(value).map_err(|rejected| value = rejected).ok())
     }
 
     /// Swaps the current value with `value` and returns the old one.
@@ -82,47 +245,403 @@ impl<T> Keep<T>
     /// if you want to swap the value a keep use `Keep::swap(..)` instead.
     pub fn swap(&self, value: impl Heaped<T>) -> Guard<T>
     {
-        self.load().swap(value)
+        let mut value = value.heap_ptr();
+        self.with_tracked(|tracked| tracked.swap(value).map_err(|rejected| value = rejected).ok())
     }
 
     /// Exchanges the value with `new` if the current value is `current`.
     ///
-    /// This does not check for semantic equality, instead the pointers that guarded are compared
+    /// This does not check for semantic equality, instead the stored values are compared by identity,
+    /// so a new value that happens to be allocated at the address of `current` does not match.
     ///
     /// # Returns
     /// * `Ok(Guard<T>)` containing the old value on success (actual == `current`)
     /// * `Err(Guard<T>)` containing the actual current value on failure (actual != `current`)
     pub fn exchange(&self, current: &Guard<T>, new: impl Heaped<T>) -> Result<Guard<T>, Guard<T>>
     {
-        self.load().exchange(current, new)
+        let mut new = new.heap_ptr();
+        let result = self.with_tracked(|tracked| Self::try_exchange(tracked, current, &mut new));
+
+        if result.is_err()
+        {
+            self.discard(new);
+        }
+
+        result
+    }
+
+    /// Returns a watcher, that blocks until new values are stored in this keep.
+    ///
+    /// The watcher keeps the tracked atomic of this keep alive and does not follow `Keep::swap_with(..)`.
+    pub fn subscribe(&self) -> Watcher<T>
+    {
+        Watcher::new(self.clone())
+    }
+
+    /// Returns a future that resolves to the next value stored in this keep.
+    ///
+    /// The value that is current when this is called counts as seen. The future only relies on the `Waker`
+    /// of its context, so it works with any executor. Like `Keep::subscribe()`, it keeps the tracked atomic
+    /// of this keep alive and does not follow `Keep::swap_with(..)`.
+    pub fn changed(&self) -> impl Future<Output = Guard<T>> + use<T>
+    {
+        Changed::new(self.clone())
+    }
+
+    /// Blocks until the value of this keep is not the one guarded by `seen` anymore and returns the new value.
+    ///
+    /// Returns right away if the value has already been replaced.
+    pub fn wait_for_change(&self, seen: &Guard<T>) -> Guard<T>
+    {
+        self.wait_until_changed(seen, None)
+            .expect("waiting without a deadline only returns once the value changed")
+    }
+
+    /// Like `Keep::wait_for_change(..)`, but gives up after `timeout` and returns `None` then.
+    pub fn wait_for_change_timeout(&self, seen: &Guard<T>, timeout: Duration) -> Option<Guard<T>>
+    {
+        self.wait_until_changed(seen, Some(Instant::now() + timeout))
+    }
+
+    /// Returns the version of the current value, which counts the values stored in this keep before it.
+    ///
+    /// Versions belong to the tracked atomic of a keep, so `Keep::swap_with(..)` swaps them along with the values.
+    pub fn version(&self) -> u64
+    {
+        self.with_tracked(|tracked| tracked.version())
+    }
+
+    /// Returns the amount of guard nodes that are currently allocated to register guards of this keep.
+    ///
+    /// Nodes are reused by later guards once they are dropped and are freed again after spikes of many guards.
+    pub fn node_count(&self) -> usize
+    {
+        self.with_tracked(|tracked| Some(tracked.node_count()))
+    }
+
+    /// Returns whether this keep still holds the value guarded by `guard`.
+    #[inline]
+    pub(crate) fn holds(&self, guard: &Guard<T>) -> bool
+    {
+        self.load().holds(guard)
+    }
+
+    #[inline]
+    pub(crate) fn watch(&self)
+    {
+        self.load().watch();
     }
 
     #[inline]
-    fn load(&self) -> &TrackedAtomic<T>
+    pub(crate) fn unwatch(&self, id: u64)
+    {
+        self.load().unwatch(id);
+    }
+
+    #[inline]
+    pub(crate) fn register_waker(&self, id: u64, seen: &Guard<T>, waker: &Waker) -> bool
+    {
+        self.load().register_waker(id, seen, waker)
+    }
+
+    /// Exchanges the value of `tracked` with `new` if it is `current`, see `TrackedAtomic::exchange(..)`.
+    ///
+    /// `new` is handed back through the reference if the exchange fails.
+    fn try_exchange(
+        tracked: &TrackedAtomic<T>,
+        current: &Guard<T>,
+        new: &mut HeapPtr<T>,
+    ) -> Option<Result<Guard<T>, Guard<T>>>
+    {
+        match tracked.exchange(current, *new)
+        {
+            Ok(old) => Some(Ok(old)),
+            Err((actual, rejected)) =>
+            {
+                *new = rejected;
+                actual.map(Err)
+            }
+        }
+    }
+
+    /// Frees `value`, which has never been stored, or hands it to the reclaimer of this keep.
+    pub(crate) fn discard(&self, value: HeapPtr<T>)
     {
-        unsafe { &*self.tracked_atomic.load(Ordering::SeqCst) }
+        self.load().discard(value);
+    }
+
+    fn wait_until_changed(&self, seen: &Guard<T>, deadline: Option<Instant>) -> Option<Guard<T>>
+    {
+        // A tracked atomic that dies while waiting counts as changed, the keep refers to another one then
+        let changed = self.load().wait_for_change(seen, deadline);
+        changed.then(|| self.read())
+    }
+
+    /// Protects the current tracked atomic from being freed while it is in use.
+    #[inline]
+    fn load(&self) -> Protected<'_, TrackedAtomic<T>>
+    {
+        let protected = hazard::protect(&self.tracked_atomic);
+
+        match self.moved_cell(protected.as_ptr())
+        {
+            Some(cell) =>
+            {
+                drop(protected);
+                mcas::protect(&cell.tracked_atomic)
+            }
+
+            None => protected,
+        }
+    }
+
+    /// Returns the current tracked atomic, which this keep holds a reference to.
+    ///
+    /// It can only be dereferenced while no other thread could swap this keep.
+    #[inline]
+    fn tracked_atomic(&self) -> *mut TrackedAtomic<T>
+    {
+        let tracked_atomic = self.tracked_atomic.load(Ordering::SeqCst);
+
+        match self.moved_cell(tracked_atomic)
+        {
+            Some(cell) => mcas::load(&cell.tracked_atomic),
+            None => tracked_atomic,
+        }
+    }
+
+    /// Returns the cell of this keep, moving its tracked atomic into a new one first if it is still stored inline.
+    fn cell(&self) -> &Cell<T>
+    {
+        let tracked_atomic = self.tracked_atomic.load(Ordering::SeqCst);
+
+        if let Some(cell) = self.moved_cell(tracked_atomic)
+        {
+            return cell;
+        }
+
+        let cell = Box::into_raw(Box::new(Cell {
+            tracked_atomic: AtomicPtr::new(tracked_atomic),
+            refs: AtomicUsize::new(1),
+        }));
+
+        // Keeps only ever change once they are moved into a cell, so only another thread moving it can interfere
+        match self.tracked_atomic.compare_exchange(
+            tracked_atomic,
+            (cell as usize | CELL) as *mut _,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        )
+        {
+            Ok(_) => unsafe { &*cell },
+            Err(actual) =>
+            {
+                drop(unsafe { Box::from_raw(cell) });
+                self.moved_cell(actual).expect("keeps only change after moving into a cell")
+            }
+        }
+    }
+
+    /// Returns the cell `tracked_atomic` points to, if it has been loaded from a keep that moved into one.
+    #[inline]
+    fn moved_cell(&self, tracked_atomic: *mut TrackedAtomic<T>) -> Option<&Cell<T>>
+    {
+        let cell = (tracked_atomic as usize & !CELL) as *const Cell<T>;
+        (tracked_atomic as usize & CELL != 0).then(|| unsafe { &*cell })
+    }
+
+    /// Runs `f` on the current tracked atomic, until it is run on one that is not dead.
+    ///
+    /// The tracked atomic can die while `f` runs on it, if this keep was pointed to another one
+    /// in the meantime and the last keep referencing it was dropped.
+    #[inline]
+    fn with_tracked<R>(&self, mut f: impl FnMut(&TrackedAtomic<T>) -> Option<R>) -> R
+    {
+        loop
+        {
+            if let Some(result) = f(&self.load())
+            {
+                break result;
+            }
+        }
+    }
+}
+
+
+impl<T> Keep<T>
+{
+    /// Returns the value, if this is the last keep of it and it is not guarded anymore.
+    ///
+    /// Weak keeps do not prevent this, they fail to upgrade afterwards.
+    /// Values held by an arc must not be shared by any other arc either.
+    /// Otherwise this keep is returned unchanged.
+    pub fn try_unwrap(self) -> Result<T, Self>
+    {
+        // This keep holds a reference to its tracked atomic, so it cannot have been freed.
+        let tracked_atomic = unsafe { &*self.tracked_atomic() };
+
+        match tracked_atomic.try_take()
+        {
+            Some(record) =>
+            {
+                if let Some(cell) = self.moved_cell(self.tracked_atomic.load(Ordering::SeqCst))
+                {
+                    unsafe { Cell::<T>::release(cell as *const Cell<T> as *mut ()) };
+                }
+
+                mem::forget(self);
+                Ok(unsafe { Record::into_inner(record) })
+            }
+
+            None => Err(self),
+        }
+    }
+
+    /// Replaces the value with the result of `f` and returns the replaced value.
+    ///
+    /// If another thread changes the value while `f` is running, `f` is called again with the new value.
+    pub fn rcu(&self, mut f: impl FnMut(&T) -> T) -> Guard<T>
+    {
+        match self.fetch_update(|current| Some(f(current)))
+        {
+            Ok(old) | Err(old) => old,
+        }
+    }
+
+    /// Replaces the value with the result of `f`, unless it returns `None`.
+    ///
+    /// If another thread changes the value while `f` is running, `f` is called again with the new value.
+    /// The allocation of a value that lost the race is reused for the next result of `f`,
+    /// unless this keep has a reclaimer, which receives the value instead.
+    ///
+    /// # Returns
+    /// * `Ok(Guard<T>)` containing the replaced value, if `f` returned a new value
+    /// * `Err(Guard<T>)` containing the current value, if `f` returned `None`
+    pub fn fetch_update(&self, mut f: impl FnMut(&T) -> Option<T>) -> Result<Guard<T>, Guard<T>>
+    {
+        let mut current = self.read();
+        let mut spare: Option<Box<MaybeUninit<T>>> = None;
+
+        loop
+        {
+            let Some(value) = f(&current)
+            else
+            {
+                break Err(current);
+            };
+
+            let mut new = match spare.take()
+            {
+                Some(spare) => Box::write(spare, value),
+                None => Box::new(value),
+            }
+            .heap_ptr();
+
+            match self.with_tracked(|tracked| Self::try_exchange(tracked, &current, &mut new))
+            {
+                Ok(old) => break Ok(old),
+                Err(actual) =>
+                {
+                    // The new value was never shared, so only its value is dropped and its box is kept,
+                    // unless it has been moved into an arc for a keep that holds every value in one
+                    // or it belongs to the reclaimer
+                    match new.is_arc() || self.load().has_reclaimer()
+                    {
+                        true => self.discard(new),
+                        false =>
+                        {
+                            unsafe { ptr::drop_in_place(new.as_ptr()) };
+                            spare = Some(unsafe { Box::from_raw(new.as_ptr().cast()) });
+                        }
+                    }
+
+                    current = actual;
+                }
+            }
+        }
     }
 }
 
 
-impl<T> Clone for Keep<T>
+impl<T: ?Sized> Clone for Keep<T>
 {
     fn clone(&self) -> Self
     {
-        let tracked_atomic = self.tracked_atomic.load(Ordering::SeqCst);
-        unsafe { &*tracked_atomic }.register_keep();
+        loop
+        {
+            let tracked_atomic = self.load();
 
-        Self {
-            tracked_atomic: AtomicPtr::new(tracked_atomic),
+            if tracked_atomic.try_register_keep()
+            {
+                break Self::from_registered(&*tracked_atomic as *const _ as *mut _);
+            }
         }
     }
 }
 
 
-impl<T> Drop for Keep<T>
+impl<T: ?Sized> Drop for Keep<T>
 {
     fn drop(&mut self)
     {
-        unsafe { &*self.tracked_atomic.load(Ordering::SeqCst) }.unregister_keep();
+        // This keep holds a reference to its tracked atomic, so it cannot have been freed.
+        unsafe { &*self.tracked_atomic() }.unregister_keep();
+
+        if let Some(cell) = self.moved_cell(self.tracked_atomic.load(Ordering::SeqCst))
+        {
+            unsafe { Cell::<T>::release(cell as *const Cell<T> as *mut ()) };
+        }
+    }
+}
+
+
+/// The tracked atomic of a keep that has been swapped.
+///
+/// Threads helping a swap might still access the tracked atomics of both keeps after they are dropped,
+/// so keeps move them into a cell of their own once they are swapped for the first time,
+/// which lives until no swap can access it anymore.
+struct Cell<T: ?Sized>
+{
+    tracked_atomic: AtomicPtr<TrackedAtomic<T>>,
+
+    /// The keep of this cell plus every swap that might still access it.
+    refs: AtomicUsize,
+}
+
+
+impl<T: ?Sized> Cell<T>
+{
+    /// Swaps the tracked atomics of two cells in a single atomic step, if they still hold the given ones.
+    fn swap(
+        cell: &Self,
+        tracked_atomic: *mut TrackedAtomic<T>,
+        other: &Self,
+        other_tracked_atomic: *mut TrackedAtomic<T>,
+    ) -> bool
+    {
+        mcas::mcas(
+            vec![
+                Word::new(&cell.tracked_atomic, tracked_atomic, other_tracked_atomic),
+                Word::new(&other.tracked_atomic, other_tracked_atomic, tracked_atomic),
+            ],
+            vec![cell.anchor(), other.anchor()],
+        )
+    }
+
+    /// Keeps this cell alive until the anchor is released.
+    fn anchor(&self) -> Anchor
+    {
+        self.refs.fetch_add(1, Ordering::SeqCst);
+        unsafe { Anchor::new(self as *const Self as *mut (), Self::release) }
+    }
+
+    unsafe fn release(this: *mut ())
+    {
+        let this = this as *mut Self;
+
+        if unsafe { &*this }.refs.fetch_sub(1, Ordering::SeqCst) == 1
+        {
+            drop(unsafe { Box::from_raw(this) });
+        }
     }
 }
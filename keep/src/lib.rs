@@ -1,36 +1,20 @@
 mod guard;
+mod hazard;
 mod heap_ptr;
 mod keep;
+mod mcas;
+mod pinned;
 mod tracked_atomic;
+mod transaction;
+mod watcher;
+mod weak_keep;
 
 
-use std::sync::atomic::{AtomicPtr, Ordering};
-
-
-pub use guard::Guard;
+pub use guard::{Guard, MappedGuard};
 pub use heap_ptr::{HeapPtr, Heaped};
 pub use keep::{Keep, KeepMarker};
+pub use pinned::Pinned;
+pub use transaction::{Snapshot, Transaction, snapshot, transaction};
+pub use watcher::Watcher;
+pub use weak_keep::WeakKeep;
 
-
-pub(crate) fn atomic_swap<T>(a: &AtomicPtr<T>, b: &AtomicPtr<T>)
-{
-    let mut ptr_a = a.load(Ordering::SeqCst);
-    let mut ptr_b = b.load(Ordering::SeqCst);
-
-    loop
-    {
-        if let Err(changed) = a.compare_exchange(ptr_a, ptr_b, Ordering::SeqCst, Ordering::SeqCst)
-        {
-            ptr_a = changed;
-            continue;
-        }
-
-        if let Err(changed) = b.compare_exchange(ptr_b, ptr_a, Ordering::SeqCst, Ordering::SeqCst)
-        {
-            ptr_b = changed;
-            continue;
-        }
-
-        break;
-    }
-}
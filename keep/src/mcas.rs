@@ -0,0 +1,390 @@
+use crate::hazard::{self, Protected};
+use std::{
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU8, AtomicUsize, Ordering},
+};
+
+
+const UNDECIDED: u8 = 0;
+const SUCCEEDED: u8 = 1;
+const FAILED: u8 = 2;
+
+/// Tags descriptors installed into a word, in the lowest bit.
+const DESCRIPTOR: usize = 1;
+
+/// Tags pending installations of a descriptor, in the second lowest bit.
+const PENDING: usize = 2;
+
+
+/// A word changed by `mcas(..)` from `old` to `new`.
+pub(crate) struct Word
+{
+    location: *const AtomicPtr<()>,
+    old: *mut (),
+    new: *mut (),
+}
+
+
+impl Word
+{
+    pub fn new<T>(location: &AtomicPtr<T>, old: *mut T, new: *mut T) -> Self
+    {
+        Self {
+            location: (location as *const AtomicPtr<T>).cast(),
+            old: old.cast(),
+            new: new.cast(),
+        }
+    }
+
+    #[inline]
+    fn location(&self) -> &AtomicPtr<()>
+    {
+        unsafe { &*self.location }
+    }
+}
+
+
+/// Keeps the location of a word allocated for threads helping `mcas(..)`, until `release` is called with `ptr`.
+pub(crate) struct Anchor
+{
+    ptr: *mut (),
+    release: unsafe fn(*mut ()),
+}
+
+
+impl Anchor
+{
+    /// # Safety
+    /// `release` must be safe to call with `ptr` from any thread, once.
+    pub unsafe fn new(ptr: *mut (), release: unsafe fn(*mut ())) -> Self
+    {
+        Self { ptr, release }
+    }
+}
+
+
+/// A multi-word compare-and-swap in progress, installed into its words in place of their values.
+///
+/// Words holding the descriptor hold their old values while it is undecided or failed and their new values
+/// once it succeeded, so every word changes at once when it is decided. Descriptors are installed in the order
+/// of their locations, so that descriptors helping each other never go around in circles.
+struct Descriptor
+{
+    status: AtomicU8,
+
+    /// The threads helping this descriptor plus its pending installations.
+    ///
+    /// Every reference uninstalls the descriptor from its words before it is released,
+    /// so the descriptor is unreachable once the last one is gone.
+    refs: AtomicUsize,
+
+    /// The words to change, ordered by their locations.
+    words: Vec<Word>,
+    anchors: Vec<Anchor>,
+}
+
+
+impl Descriptor
+{
+    #[inline]
+    fn tagged(&self) -> *mut ()
+    {
+        (self as *const Self as usize | DESCRIPTOR) as *mut ()
+    }
+
+    /// Takes another reference to this descriptor, unless it is already unreachable.
+    fn try_acquire(&self) -> bool
+    {
+        self.refs
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |refs| (refs != 0).then_some(refs + 1))
+            .is_ok()
+    }
+
+    fn release(&self)
+    {
+        if self.refs.fetch_sub(1, Ordering::SeqCst) == 1
+        {
+            // Threads that found the descriptor before it was uninstalled might still read it
+            unsafe { hazard::retire(self.tagged(), Self::free) };
+        }
+    }
+
+    /// Installs this descriptor into every word, decides it and uninstalls it again.
+    ///
+    /// Returns whether it succeeded. The caller must hold a reference to the descriptor.
+    fn help(&self) -> bool
+    {
+        let tagged = self.tagged();
+
+        if self.status.load(Ordering::SeqCst) == UNDECIDED
+        {
+            let mut status = SUCCEEDED;
+
+            'words: for (index, word) in self.words.iter().enumerate()
+            {
+                loop
+                {
+                    // Another thread decided it already
+                    if self.status.load(Ordering::SeqCst) != UNDECIDED
+                    {
+                        break 'words;
+                    }
+
+                    let protected = hazard::protect(word.location());
+                    let actual = protected.as_ptr();
+
+                    if actual == tagged
+                    {
+                        break;
+                    }
+
+                    if is_tagged(actual)
+                    {
+                        assist(word.location(), actual, self);
+                        continue;
+                    }
+
+                    if actual != word.old
+                    {
+                        status = FAILED;
+                        break 'words;
+                    }
+
+                    drop(protected);
+                    self.install(index);
+                }
+            }
+
+            let _ = self
+                .status
+                .compare_exchange(UNDECIDED, status, Ordering::SeqCst, Ordering::SeqCst);
+        }
+
+        let succeeded = self.status.load(Ordering::SeqCst) == SUCCEEDED;
+
+        for word in &self.words
+        {
+            let value = match succeeded
+            {
+                true => word.new,
+                false => word.old,
+            };
+
+            let _ = word
+                .location()
+                .compare_exchange(tagged, value, Ordering::SeqCst, Ordering::SeqCst);
+        }
+
+        succeeded
+    }
+
+    /// Installs this descriptor into the word at `index`, if it still holds its old value
+    /// and the descriptor is still undecided once the installation is pending.
+    fn install(&self, index: usize)
+    {
+        let location = self.words[index].location();
+
+        // The pending installation holds its own reference, as it might be completed by another thread
+        self.refs.fetch_add(1, Ordering::SeqCst);
+
+        let pending = Box::into_raw(Box::new(Pending {
+            descriptor: self,
+            index,
+        }));
+        let tagged = (pending as usize | PENDING) as *mut ();
+
+        match location.compare_exchange(self.words[index].old, tagged, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Ok(_) =>
+            {
+                Pending::complete(tagged, location);
+                unsafe { hazard::retire(tagged, Pending::free) };
+            }
+
+            Err(_) =>
+            {
+                // The installation has never been published and the caller still holds a reference
+                drop(unsafe { Box::from_raw(pending) });
+                self.refs.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    unsafe fn free(tagged: *mut ())
+    {
+        let this = unsafe { Box::from_raw(untag(tagged) as *mut Self) };
+
+        for anchor in &this.anchors
+        {
+            unsafe { (anchor.release)(anchor.ptr) };
+        }
+    }
+}
+
+
+/// An installation of a descriptor into one of its words, that only takes effect if the descriptor is still
+/// undecided afterwards.
+///
+/// Without it, a thread could install a descriptor it found undecided after it has been decided, into a word
+/// that happens to hold its old value again.
+struct Pending
+{
+    descriptor: *const Descriptor,
+    index: usize,
+}
+
+
+impl Pending
+{
+    /// Replaces the pending installation `tagged` in `location` with its descriptor if that is still undecided,
+    /// or with the old value of its word otherwise.
+    ///
+    /// Returns the descriptor if this installed it. `tagged` must be protected, which keeps the descriptor alive.
+    fn complete<'a>(tagged: *mut (), location: &AtomicPtr<()>) -> Option<&'a Descriptor>
+    {
+        let pending = unsafe { &*(untag(tagged) as *const Self) };
+        let descriptor = unsafe { &*pending.descriptor };
+
+        let value = match descriptor.status.load(Ordering::SeqCst) == UNDECIDED
+        {
+            true => descriptor.tagged(),
+            false => descriptor.words[pending.index].old,
+        };
+
+        let installed = location
+            .compare_exchange(tagged, value, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok();
+
+        (installed && value == descriptor.tagged()).then_some(descriptor)
+    }
+
+    unsafe fn free(tagged: *mut ())
+    {
+        let this = unsafe { Box::from_raw(untag(tagged) as *mut Self) };
+        unsafe { &*this.descriptor }.release();
+    }
+}
+
+
+#[inline]
+fn is_tagged<T>(ptr: *mut T) -> bool
+{
+    ptr as usize & (DESCRIPTOR | PENDING) != 0
+}
+
+
+#[inline]
+fn untag<T>(ptr: *mut T) -> *mut T
+{
+    (ptr as usize & !(DESCRIPTOR | PENDING)) as *mut T
+}
+
+
+/// Helps finishing the descriptor or pending installation `tagged`, which has been found in `location`
+/// and is protected by the caller, unless it belongs to `helping`, which the calling thread is helping already.
+fn assist(location: &AtomicPtr<()>, tagged: *mut (), helping: *const Descriptor)
+{
+    if tagged as usize & PENDING != 0
+    {
+        // The protected installation holds a reference to its descriptor, which can be helped without taking another.
+        // This thread installed the descriptor, so it has to uninstall it again before the reference is gone.
+        if let Some(descriptor) = Pending::complete(tagged, location)
+            && !ptr::eq(descriptor, helping)
+        {
+            descriptor.help();
+        }
+
+        return;
+    }
+
+    // A descriptor without references left has been uninstalled already
+    let descriptor = unsafe { &*(untag(tagged) as *const Descriptor) };
+
+    if !ptr::eq(descriptor, helping) && descriptor.try_acquire()
+    {
+        descriptor.help();
+        descriptor.release();
+    }
+}
+
+
+/// Changes every word from its old to its new value in a single atomic step, if all of them hold their old values.
+///
+/// Returns whether the words have been changed. Locations must be distinct and may only hold values whose
+/// two lowest bits are clear, every access to them has to go through this module. `anchors` keep the locations
+/// alive for threads that help finishing the change, they are released once none of them can access them anymore.
+pub(crate) fn mcas(mut words: Vec<Word>, anchors: Vec<Anchor>) -> bool
+{
+    words.sort_unstable_by_key(|word| word.location as usize);
+
+    let descriptor: &Descriptor = Box::leak(Box::new(Descriptor {
+        status: AtomicU8::new(UNDECIDED),
+        refs: AtomicUsize::new(1),
+        words,
+        anchors,
+    }));
+
+    let succeeded = descriptor.help();
+    descriptor.release();
+
+    succeeded
+}
+
+
+/// Protects the value of `atomic`, finishing any `mcas(..)` in progress on it first.
+pub(crate) fn protect<T>(atomic: &AtomicPtr<T>) -> Protected<'_, T>
+{
+    let location = unsafe { &*(atomic as *const AtomicPtr<T>).cast::<AtomicPtr<()>>() };
+
+    loop
+    {
+        let protected = hazard::protect(atomic);
+        let tagged = protected.as_ptr();
+
+        if !is_tagged(tagged)
+        {
+            break protected;
+        }
+
+        assist(location, tagged.cast(), ptr::null());
+    }
+}
+
+
+/// Returns the value of `atomic`, finishing any `mcas(..)` in progress on it first.
+pub(crate) fn load<T>(atomic: &AtomicPtr<T>) -> *mut T
+{
+    protect(atomic).as_ptr()
+}
+
+
+/// Stores `new` into `atomic` if it holds `current`, finishing any `mcas(..)` in progress on it first.
+///
+/// Fails with the actual value otherwise.
+pub(crate) fn compare_exchange<T>(atomic: &AtomicPtr<T>, current: *mut T, new: *mut T) -> Result<*mut T, *mut T>
+{
+    loop
+    {
+        match atomic.compare_exchange(current, new, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Err(actual) if is_tagged(actual) => drop(protect(atomic)),
+            result => break result,
+        }
+    }
+}
+
+
+/// Stores `new` into `atomic` and returns its old value, finishing any `mcas(..)` in progress on it first.
+pub(crate) fn swap<T>(atomic: &AtomicPtr<T>, new: *mut T) -> *mut T
+{
+    let mut current = load(atomic);
+
+    loop
+    {
+        match compare_exchange(atomic, current, new)
+        {
+            Ok(old) => break old,
+            Err(actual) => current = actual,
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use crate::{Guard, Keep};
+use std::ops::Deref;
+
+
+/// A value pinned by `Keep::pin()`, which can be read any amount of times without registering a guard again.
+///
+/// Moving on to a newer value reuses the registration of the pinned one, see `Pinned::refresh(..)`.
+pub struct Pinned<'a, T: ?Sized>
+{
+    keep: &'a Keep<T>,
+    guard: Guard<T>,
+}
+
+
+impl<'a, T: ?Sized> Pinned<'a, T>
+{
+    pub(crate) fn new(keep: &'a Keep<T>, guard: Guard<T>) -> Self
+    {
+        Self { keep, guard }
+    }
+
+    /// Pins the current value of the keep instead of the pinned one.
+    ///
+    /// Returns whether the value changed since it was pinned.
+    #[inline]
+    pub fn refresh(&mut self) -> bool
+    {
+        self.keep.refresh(&mut self.guard)
+    }
+
+    /// Returns a guard of the pinned value, which can outlive the pin.
+    #[inline]
+    pub fn guard(&self) -> Guard<T>
+    {
+        self.guard.clone()
+    }
+}
+
+
+impl<T: ?Sized> Deref for Pinned<'_, T>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T
+    {
+        &self.guard
+    }
+}
+
+
+impl<T: ?Sized> AsRef<T> for Pinned<'_, T>
+{
+    fn as_ref(&self) -> &T
+    {
+        &self.guard
+    }
+}
+
+
+impl<T: ?Sized + std::fmt::Debug> std::fmt::Debug for Pinned<'_, T>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        f.debug_struct("Pinned")
+            .field("reference", &self.as_ref())
+            .finish()
+    }
+}
@@ -1,307 +1,1696 @@
-use crate::{Guard, HeapPtr, Heaped};
+use crate::{
+    Guard, HeapPtr, Heaped,
+    hazard::{self, Protected},
+    heap_ptr::Holder,
+    mcas::{self, Anchor, Word},
+};
+use parking_lot::{Condvar, Mutex};
 use std::{
+    alloc::{self, Layout},
+    mem::{self, ManuallyDrop, MaybeUninit},
     ptr,
-    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+    sync::atomic::{self, AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    task::Waker,
+    thread,
+    time::Instant,
 };
 
 
-pub struct TrackedAtomic<T>
+/// The id of the next tracked atomic.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+
+/// The keep count of a tracked atomic while its last keep is being unwrapped, see `TrackedAtomic::try_take()`.
+const UNWRAPPING: usize = usize::MAX;
+
+
+/// Receives boxed values once they are no longer referenced, instead of dropping them, see `Keep::with_reclaimer(..)`.
+pub type Reclaimer<T> = Box<dyn Fn(Box<T>) + Send + Sync>;
+
+
+/// A failed exchange, with the actual current value unless the tracked atomic is dead, and the rejected new value.
+pub type Rejected<T> = (Option<Guard<T>>, HeapPtr<T>);
+
+
+/// The state of a tracked atomic, which lives in a single allocation together with its domain.
+///
+/// The domain comes first, so that the whole allocation can be freed through it once its last guard is gone.
+#[repr(C)]
+pub struct TrackedAtomic<T: ?Sized>
 {
-    ptr: HeapPtr<AtomicPtr<T>>,
-    guard_ptr: HeapPtr<AtomicPtr<GuardNode<T>>>,
-    domain: HeapPtr<GuardNode<T>>,
-    keep_count: HeapPtr<AtomicUsize>,
+    domain: Domain<T>,
+    keep_count: AtomicUsize,
+
+    /// The amount of weak keeps, plus one shared by all keeps.
+    weak_count: AtomicUsize,
+
+    /// Tells this tracked atomic apart from any other one that is allocated at the same address later.
+    id: u64,
 }
 
 
-impl<T> TrackedAtomic<T>
+// A tracked atomic hands out references to its value to every thread holding a keep or guard
+// and its values are freed by whichever thread drops the last reference to them.
+unsafe impl<T: ?Sized + Send + Sync> Send for TrackedAtomic<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for TrackedAtomic<T> {}
+
+
+impl<T: ?Sized> TrackedAtomic<T>
 {
-    pub fn new(value: impl Heaped<T>) -> Self
+    /// Creates a tracked atomic holding `value`, that moves every stored value into an arc if `arcs` is set.
+    ///
+    /// Boxed values are handed to `reclaimer` once they are no longer referenced, if there is one.
+    pub fn new(value: impl Heaped<T>, arcs: bool, reclaimer: Option<Reclaimer<T>>) -> HeapPtr<Self>
     {
-        let value = value.heap_ptr();
+        let mut value = value.heap_ptr();
+
+        if arcs
+        {
+            value = unsafe { value.into_arc() };
+        }
 
-        let domain = GuardNode::new(ptr::null_mut(), ptr::null_mut()).heap_ptr();
-        unsafe { &mut *domain.as_ptr() }.head = domain.as_ptr();
+        let this = Self {
+            domain: Domain::new(value, arcs, reclaimer),
+            keep_count: AtomicUsize::new(0),
+            weak_count: AtomicUsize::new(1),
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        }
+        .heap_ptr();
 
-        let guard_ptr = AtomicPtr::new(domain.as_ref().register(value.as_ptr())).heap_ptr();
+        // The first guard nodes point into the allocation, so they can only be set up once it is in place
+        unsafe { Domain::init(&raw mut (*this.as_ptr()).domain) };
+        this
+    }
 
+    /// Reads the current value of this tracked atomic.
+    ///
+    /// Returns `None` if this tracked atomic is already dead.
+    pub fn read(&self) -> Option<Guard<T>>
+    {
+        let domain = &self.domain;
 
-        Self {
-            ptr: AtomicPtr::new(value.as_ptr()).heap_ptr(),
-            guard_ptr,
-            domain,
-            keep_count: AtomicUsize::new(0).heap_ptr(),
+        loop
+        {
+            let record = self.current()?;
+            let guard_node = domain.register(record);
+
+            // If the value is still current after registering it, it cannot have been retired
+            // before the registration and every reclamation will see it.
+            if self.is_current(record)
+            {
+                return Some(Guard::new(guard_node, record));
+            }
+
+            unsafe { &*guard_node }.unregister(record);
         }
     }
 
-    pub fn read(&self) -> Guard<T>
+    /// Returns the version of the current value, which counts the values stored before it.
+    ///
+    /// Returns `None` if this tracked atomic is already dead.
+    pub fn version(&self) -> Option<u64>
+    {
+        let record = self.announce()?;
+        let version = record.version;
+        self.unprotect(record);
+
+        Some(version)
+    }
+
+    /// Returns the id of this tracked atomic, which no other tracked atomic ever has.
+    #[inline]
+    pub fn id(&self) -> u64
     {
-        let ptr = self.ptr.as_ref().load(Ordering::SeqCst);
-        let guard_node = self.domain.as_ref().register(ptr);
-        Guard::new(guard_node, ptr)
+        self.id
     }
 
-    /// Stores a new value in this tracked atomic
-    pub fn write(&self, value: impl Heaped<T>)
+    /// Protects the current value from being reclaimed without registering a guard for it.
+    ///
+    /// Returns `None` if this tracked atomic is already dead.
+    pub fn protect(&self) -> Option<Protected<'_, T>>
     {
-        let value = value.heap_ptr();
+        self.announce()
+            .map(|record| record.map(|record| record.value.as_ptr()))
+    }
 
-        // Store the new value and create a guard node for it
-        let old = self.ptr.as_ref().swap(value.as_ptr(), Ordering::SeqCst);
-        let node = self.domain.as_ref().register(value.as_ptr());
+    /// Releases a protection created by `TrackedAtomic::protect()`.
+    pub fn unprotect<U: ?Sized>(&self, value: Protected<'_, U>)
+    {
+        let record = value.announced();
+        drop(value);
 
-        // now store the guard and unregister the old value
-        let old_node = self.guard_ptr.as_ref().swap(node, Ordering::SeqCst);
-        unsafe { &*old_node }.unregister(old);
+        // The value might have been replaced and retired while it was protected
+        if mcas::load(&self.domain.current).cast() != record
+        {
+            self.domain.reclaim();
+        }
     }
 
-    /// Swaps the current value with `value` and returns the old one.
-    pub fn swap(&self, value: impl Heaped<T>) -> Guard<T>
+    /// Points `guard` to the current value, reusing its registration.
+    ///
+    /// Returns whether the value changed, or `None` if this tracked atomic is already dead.
+    pub fn refresh(&self, guard: &mut Guard<T>) -> Option<bool>
     {
-        let value = value.heap_ptr();
+        let domain = &self.domain;
+        let guard_node = unsafe { &*guard.guard_node() };
+
+        // The registration can only be reused within the same domain
+        if !ptr::eq(guard_node.domain, domain)
+        {
+            *guard = self.read()?;
+            return Some(true);
+        }
+
+        let old = guard.record();
+
+        loop
+        {
+            let record = self.current()?;
+
+            if record == guard.record()
+            {
+                break;
+            }
+
+            // The new value is protected until the node guards it, so that the guard never points to a value
+            // its node does not guard, even if this tracked atomic dies in the meantime
+            let protected = hazard::announce(record);
+
+            if self.is_current(record)
+            {
+                guard_node.value.store(record.cast(), Ordering::SeqCst);
+                drop(protected);
 
-        // Store the new value and create a guard node for it
-        let old = self.ptr.as_ref().swap(value.as_ptr(), Ordering::SeqCst);
-        let node = self.domain.as_ref().register(value.as_ptr());
+                unsafe { guard.set_record(record) };
+                break;
+            }
+        }
 
-        // Now store the guard node
-        let old_node = self.guard_ptr.as_ref().swap(node, Ordering::SeqCst);
+        // The old value is not guarded by this node anymore, so it might be reclaimable
+        let changed = old != guard.record();
+
+        if changed
+        {
+            domain.reclaim();
+        }
 
-        // The old node can be reused for the returned guard
-        Guard::new(old_node, old)
+        Some(changed)
     }
 
-    /// Exchanges the value with `new` if the current value is `current`.
+    /// Stores a new value in this tracked atomic.
     ///
-    /// This does not check for semantic equality, instead the pointers that guarded are compared
+    /// Fails with `value` if this tracked atomic is already dead.
+    pub fn write(&self, value: HeapPtr<T>) -> Result<(), HeapPtr<T>>
+    {
+        // Dropping the guard of the old value reclaims it, unless it is guarded elsewhere
+        self.swap(value).map(drop)
+    }
+
+    /// Swaps the current value with `value` and returns the old one.
     ///
-    /// # Returns
-    /// * `Ok(Guard<T>)` containing the old value on success (actual == `current`)
-    /// * `Err(Guard<T>)` containing the actual current value on failure (actual != `current`)
-    pub fn exchange(&self, current: &Guard<T>, new: impl Heaped<T>) -> Result<Guard<T>, Guard<T>>
+    /// Fails with `value` if this tracked atomic is already dead.
+    pub fn swap(&self, value: HeapPtr<T>) -> Result<Guard<T>, HeapPtr<T>>
     {
-        let new = new.heap_ptr();
+        // The old value is guarded before it is replaced, so the returned guard can never miss it
+        let Some(mut old) = self.read()
+        else
+        {
+            return Err(value);
+        };
 
-        match self.ptr.as_ref().compare_exchange(
-            current.as_ptr(),
-            new.as_ptr(),
-            Ordering::SeqCst,
-            Ordering::SeqCst,
-        )
+        let record = self.record_of(value);
+
+        loop
         {
-            Ok(old) =>
+            unsafe { (*record).version = old.version() + 1 };
+
+            if self.replace(old.record(), record)
             {
-                let guard_node = self.domain.as_ref().register(new.as_ptr());
-                let old_node = self.guard_ptr.as_ref().swap(guard_node, Ordering::SeqCst);
-                Ok(Guard::new(old_node, old))
+                return Ok(old);
             }
 
-            Err(cur) =>
+            if self.refresh(&mut old).is_none()
             {
-                let guard_node = self.domain.as_ref().register(cur);
-                Err(Guard::new(guard_node, cur))
+                return Err(unsafe { Record::into_value(record) });
             }
         }
     }
 
+    /// Exchanges the value with `new` if the current value is `current`.
+    ///
+    /// This does not check for semantic equality, instead the records of the values are compared,
+    /// so a new value that happens to be allocated at the address of `current` does not match.
+    ///
+    /// # Returns
+    /// * `Ok(Guard<T>)` containing the old value on success (actual == `current`)
+    /// * `Err((Some(Guard<T>), new))` containing the actual current value on failure (actual != `current`)
+    /// * `Err((None, new))` if this tracked atomic is already dead
+    pub fn exchange(&self, current: &Guard<T>, new: HeapPtr<T>) -> Result<Guard<T>, Rejected<T>>
+    {
+        let record = self.record_of(new);
+        unsafe { (*record).version = current.version() + 1 };
+
+        // The old value is still guarded by `current`, so it can be registered after it has been replaced
+        match self.replace(current.record(), record)
+        {
+            true => Ok(Guard::new(self.domain.register(current.record()), current.record())),
+            false => Err((self.read(), unsafe { Record::into_value(record) })),
+        }
+    }
+
     pub fn unregister_keep(&self)
     {
         // If there are no more keeps that reference this tracked atomic, it can be cleaned up.
-        if 1 >= self.keep_count.as_ref().fetch_sub(1, Ordering::SeqCst)
+        if 1 < self.keep_count.fetch_sub(1, Ordering::SeqCst)
         {
-            // Unregister the current value from the domain
-            unsafe { &*self.guard_ptr.as_ref().load(Ordering::SeqCst) }.unregister_self();
+            return;
+        }
 
-            unsafe {
-                // Now free everything except guard nodes
-                self.ptr.free();
-                self.keep_count.free();
+        // Kill the tracked atomic by nulling its value, so that threads still operating on it
+        // notice that it is dead instead of reading or writing a value nobody is keeping anymore.
+        // Stores compare the record they replace, so none of them can succeed afterwards.
+        let record = mcas::swap(&self.domain.current, ptr::null_mut());
+        self.domain.retire(record);
+        self.domain.notify();
 
-                // Keep a reference to the head
-                let head = self.domain;
+        // The bookkeeping survives as long as there are weak keeps left.
+        self.unregister_weak();
+    }
 
-                // Now free this struct, leaving only the guard nodes alive,
-                // which are cleaned up by unregistering nodes from the domain
-                HeapPtr::from_ptr(self as *const _ as *mut Self).free();
+    pub fn register_weak(&self)
+    {
+        self.weak_count.fetch_add(1, Ordering::SeqCst);
+    }
 
-                // Check if the guard list is already dead, in this case clean the head
-                if head.as_ref().dead.load(Ordering::SeqCst)
-                {
-                    head.free();
-                }
-                // if its not dead, indicate that the tracked atomic is dead and the head can kill itself
-                else
-                {
-                    head.as_ref().dead.store(true, Ordering::SeqCst);
-                }
-            };
+    pub fn unregister_weak(&self)
+    {
+        if 1 < self.weak_count.fetch_sub(1, Ordering::SeqCst)
+        {
+            return;
         }
+
+        // The struct itself might still be used by other threads that loaded it before it died.
+        unsafe { hazard::retire(self as *const Self as *mut (), Self::free) };
     }
 
-    pub fn register_keep(&self)
+    /// Registers a weak keep for this tracked atomic, unless its bookkeeping is already retired.
+    pub fn try_register_weak(&self) -> bool
     {
-        self.keep_count.as_ref().fetch_add(1, Ordering::SeqCst);
+        self.weak_count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| (count != 0).then_some(count + 1))
+            .is_ok()
     }
-}
 
+    /// Takes the record of the value out of this tracked atomic and kills it,
+    /// if `keep_count` proves the calling keep is the last one and no guard holds the value.
+    ///
+    /// On success the calling keep is unregistered and must not be dropped anymore.
+    pub fn try_take(&self) -> Option<*mut Record<T>>
+    {
+        let keep_count = &self.keep_count;
+
+        // Weak keeps wait for the outcome instead of upgrading, as the tracked atomic might stay alive.
+        keep_count
+            .compare_exchange(1, UNWRAPPING, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()?;
 
-pub struct GuardNode<T>
-{
-    head: *mut Self,
-    dead: AtomicBool,
-    value: AtomicPtr<T>,
-    next: AtomicPtr<Self>,
-}
+        let domain = &self.domain;
+        let reclaiming = domain.reclaiming.lock();
 
+        // Readers can only guard the value if they read it before it was nulled,
+        // in which case their registration is visible afterwards.
+        let record = mcas::swap(&domain.current, ptr::null_mut());
+        let guarded = hazard::is_protected(record.cast()) || domain.guard_count(record) != 0;
 
-impl<T> GuardNode<T>
-{
-    fn new(value: *mut T, head: *mut Self) -> Self
-    {
-        Self {
-            head,
-            dead: AtomicBool::new(false),
-            value: AtomicPtr::new(value),
-            next: AtomicPtr::new(ptr::null_mut()),
+        // Other arcs of the value could be upgraded by weak arcs at any time
+        if guarded || !unsafe { (*record).value.is_unique() }
+        {
+            // Nobody could have changed the dead tracked atomic in the meantime, so it can be revived
+            // and its version stays the same.
+            domain.current.store(record, Ordering::SeqCst);
+            keep_count.store(1, Ordering::SeqCst);
+            return None;
         }
+
+        drop(reclaiming);
+
+        keep_count.store(0, Ordering::SeqCst);
+        self.unregister_weak();
+        Some(record)
     }
 
-    /// Registers `value` inside the domain.
-    fn register(&self, value: *mut T) -> *mut Self
+    pub fn register_keep(&self)
     {
-        match self.value.compare_exchange(
-            ptr::null_mut(),
-            value,
-            Ordering::SeqCst,
-            Ordering::SeqCst,
-        )
+        self.keep_count.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Registers another keep for this tracked atomic, unless it is already dead.
+    ///
+    /// Waits while the last keep is being unwrapped, which either kills the tracked atomic or leaves it as it was.
+    pub fn try_register_keep(&self) -> bool
+    {
+        let keep_count = &self.keep_count;
+        let mut count = keep_count.load(Ordering::SeqCst);
+
+        loop
         {
-            Ok(_) => self as *const Self as _,
-            Err(_) =>
+            match count
             {
-                let node = GuardNode::new(value, self.head).heap_ptr();
-                let mut next = self.next.load(Ordering::SeqCst);
+                0 => return false,
+                UNWRAPPING =>
+                {
+                    thread::yield_now();
+                    count = keep_count.load(Ordering::SeqCst);
+                }
 
-                loop
+                _ => match keep_count.compare_exchange(count, count + 1, Ordering::SeqCst, Ordering::SeqCst)
                 {
-                    if let Some(next) = unsafe { next.as_ref() }
-                    {
-                        unsafe { node.free() }; // This node is no longer needed.
-                        break next.register(value);
-                    }
+                    Ok(_) => return true,
+                    Err(actual) => count = actual,
+                },
+            }
+        }
+    }
+
+    /// Blocks until the current value is not the one guarded by `seen` anymore, or until `deadline` has passed.
+    ///
+    /// Returns whether the value changed.
+    pub fn wait_for_change(&self, seen: &Guard<T>, deadline: Option<Instant>) -> bool
+    {
+        let extras = self.domain.extras_or_init();
+
+        // Writers only notify if there are watchers, so this has to be announced before checking the value
+        extras.watchers.fetch_add(1, Ordering::SeqCst);
+        let mut wakers = extras.wakers.lock();
+
+        let changed = loop
+        {
+            if !self.holds(seen)
+            {
+                break true;
+            }
 
-                    match self.next.compare_exchange(
-                        ptr::null_mut(),
-                        node.as_ptr(),
-                        Ordering::SeqCst,
-                        Ordering::SeqCst,
-                    )
+            match deadline
+            {
+                Some(deadline) =>
+                {
+                    if extras.changed.wait_until(&mut wakers, deadline).timed_out()
                     {
-                        Ok(_) => break node.as_ptr(),
-                        Err(actual) => next = actual,
+                        break !self.holds(seen);
                     }
                 }
+
+                None => extras.changed.wait(&mut wakers),
             }
-        }
+        };
+
+        drop(wakers);
+        extras.watchers.fetch_sub(1, Ordering::SeqCst);
+
+        changed
     }
 
-    /// Unregisters this guard from the domain if its guarding `value`
-    pub fn unregister(&self, value: *mut T) -> bool
+    /// Announces a future waiting for a new value, which has to be revoked by `unwatch(..)` again.
+    pub fn watch(&self)
     {
-        if value.is_null()
-        {
-            return false;
-        }
+        self.domain.extras_or_init().watchers.fetch_add(1, Ordering::SeqCst);
+    }
 
-        let result = self.value.compare_exchange(
-            value,
-            ptr::null_mut(),
-            Ordering::SeqCst,
-            Ordering::Relaxed,
-        );
+    /// Revokes the announcement of a waiting future and removes the waker it registered under `id`.
+    pub fn unwatch(&self, id: u64)
+    {
+        let extras = self.domain.watched();
+        extras.wakers.lock().retain(|(other, _)| *other != id);
+        extras.watchers.fetch_sub(1, Ordering::SeqCst);
+    }
 
-        // return false if this node did not unregister successfully
-        if result.is_err()
+    /// Registers `waker` to be woken once the current value is not the one guarded by `seen` anymore,
+    /// replacing the waker registered under `id` before.
+    ///
+    /// Returns whether the value changed already. The future must have been announced by `watch()` before.
+    pub fn register_waker(&self, id: u64, seen: &Guard<T>, waker: &Waker) -> bool
+    {
+        if !self.holds(seen)
         {
-            return false;
+            return true;
         }
 
-        // look if there's cleanup to do and keep track if the guard_domain holds any other guards than null
-        let mut domain_dead = true;
-        let mut mutation_over = true;
-        let mut current = unsafe { &*self.head };
-
-        while mutation_over || domain_dead
         {
-            let ptr = current.value.load(Ordering::SeqCst);
+            let mut wakers = self.domain.watched().wakers.lock();
 
-            if ptr == value
+            match wakers.iter_mut().find(|(other, _)| *other == id)
             {
-                mutation_over = false;
+                Some((_, registered)) => registered.clone_from(waker),
+                None => wakers.push((id, waker.clone())),
             }
+        }
 
-            if !ptr.is_null()
-            {
-                domain_dead = false;
-            }
+        // A value stored before the registration might not have woken the new waker
+        !self.holds(seen)
+    }
+
+    /// Returns the amount of guard nodes allocated in the domain of this tracked atomic.
+    pub fn node_count(&self) -> usize
+    {
+        self.domain.nodes.count()
+    }
+
+    /// Returns the record of the current value, or `None` if this tracked atomic is already dead.
+    ///
+    /// The record may only be dereferenced once it is guarded or protected and still current afterwards.
+    #[inline]
+    fn current(&self) -> Option<*mut Record<T>>
+    {
+        let record = mcas::load(&self.domain.current);
+        (!record.is_null()).then_some(record)
+    }
+
+    /// Returns whether the current value is the one guarded by `guard`.
+    ///
+    /// Records are compared instead of values, every store allocates a new one and the guard keeps its own alive.
+    #[inline]
+    pub fn holds(&self, guard: &Guard<T>) -> bool
+    {
+        self.is_current(guard.record())
+    }
+
+    #[inline]
+    fn is_current(&self, record: *mut Record<T>) -> bool
+    {
+        mcas::load(&self.domain.current) == record
+    }
+
+    /// Protects the record of the current value from being reclaimed.
+    ///
+    /// Returns `None` if this tracked atomic is already dead.
+    fn announce(&self) -> Option<Protected<'_, Record<T>>>
+    {
+        loop
+        {
+            let record = self.current()?;
+            let protected = hazard::announce(record);
 
-            match unsafe { current.next.load(Ordering::SeqCst).as_ref() }
+            // Like a registration, the announcement holds if the value is still current afterwards
+            if self.is_current(record)
             {
-                Some(next) => current = next,
-                None => break,
+                return Some(protected);
             }
         }
+    }
 
-        // If the mutation is over, free the associated value
-        if mutation_over
-        {
-            unsafe { HeapPtr::from_ptr(value).free() };
-        }
+    /// Allocates the record of `value` for a transaction, that replaces the value guarded by `old` with it.
+    pub fn prepare(&self, value: HeapPtr<T>, old: &Guard<T>) -> *mut Record<T>
+    {
+        let record = self.record_of(value);
+        unsafe { (*record).version = old.version() + 1 };
+
+        record
+    }
+
+    /// Returns the word of a transaction replacing `old` with `new`,
+    /// together with an anchor that keeps this tracked atomic allocated for helpers of the transaction.
+    pub fn word(&self, old: *mut Record<T>, new: *mut Record<T>) -> (Word, Anchor)
+    {
+        // The anchor counts like a guard, which keeps the domain and its tracked atomic alive
+        self.domain.refs.fetch_add(1, Ordering::SeqCst);
+
+        let word = Word::new(&self.domain.current, old, new);
+        let anchor = unsafe { Anchor::new(&self.domain as *const Domain<T> as *mut (), Domain::<T>::release_anchor) };
+
+        (word, anchor)
+    }
+
+    /// Frees `value`, which has never been stored, or hands it to the reclaimer if there is one.
+    ///
+    /// Nobody else has seen the value, so it does not have to wait for a reclamation.
+    pub fn discard(&self, value: HeapPtr<T>)
+    {
+        unsafe { self.domain.free_value(value) };
+    }
 
+    /// Returns whether values are handed to a reclaimer instead of being dropped.
+    #[inline]
+    pub fn has_reclaimer(&self) -> bool
+    {
+        self.domain.reclaimer().is_some()
+    }
+
+    /// Retires `old` and wakes watchers, once a transaction replaced it.
+    pub fn committed(&self, old: *mut Record<T>)
+    {
+        self.domain.retire(old);
+        self.domain.notify();
+    }
 
-        // If the domain is dead, free all nodes except the head if its not marked as dead.
-        if domain_dead
+    /// Allocates the record for storing `value`, moving it into an arc if every value of this tracked atomic is.
+    fn record_of(&self, value: HeapPtr<T>) -> *mut Record<T>
+    {
+        let value = match self.domain.arcs()
         {
-            let head = self.head;
+            true => unsafe { value.into_arc() },
+            false => value,
+        };
 
-            let mut current = unsafe { &*head }
-                .next
-                .swap(ptr::null_mut(), Ordering::SeqCst);
+        // The reclaimer receives the boxes of its values, so they cannot be moved into their records
+        Record::new(value, self.domain.reclaimer().is_none())
+    }
 
-            while let Some(curr) = unsafe { current.as_ref() }
-            {
-                let next = curr.next.swap(ptr::null_mut(), Ordering::SeqCst);
-                unsafe { HeapPtr::from_ptr(current).free() };
-                current = next;
-            }
+    /// Replaces `old` with `new` in a single compare-exchange and retires `old` if that succeeded.
+    fn replace(&self, old: *mut Record<T>, new: *mut Record<T>) -> bool
+    {
+        let domain = &self.domain;
 
-            // Now check if the head is dead and kill it, while marking it as dead.
-            // if its just marked as dead here, the last surviving keep will kill it on drop.
-            if unsafe { &*head }.dead.swap(true, Ordering::SeqCst)
-            {
-                unsafe { HeapPtr::from_ptr(head).free() };
-            }
+        let replaced = mcas::compare_exchange(&domain.current, old, new).is_ok();
+
+        if replaced
+        {
+            domain.retire(old);
+            domain.notify();
         }
 
-        true
+        replaced
     }
 
-    pub fn unregister_self(&self)
+    /// Frees a retired tracked atomic, as soon as its domain has no guards left.
+    unsafe fn free(this: *mut ())
     {
-        self.unregister(self.value.load(Ordering::SeqCst));
+        unsafe { Domain::release(&raw mut (*(this as *mut Self)).domain) };
     }
 }
 
 
-impl<T> Clone for TrackedAtomic<T>
+/// A value stored in a tracked atomic.
+///
+/// Pointers to unsized values take two words, so tracked atomics point to the record of their value instead,
+/// which fits into a single atomic word for every value. Every store allocates a new record,
+/// so guards and protections register the address of the record instead of the value.
+///
+/// Boxed values behind thin pointers are moved into the allocation of their record, so that they do not take
+/// an allocation of their own. Values held by arcs and values handed to a reclaimer keep their allocation.
+pub struct Record<T: ?Sized>
+{
+    value: HeapPtr<T>,
+
+    /// Counts the values stored in the tracked atomic before this one.
+    version: u64,
+
+    /// The next record on the stack of retired records of the domain, once this one has been replaced.
+    next: AtomicPtr<Record<T>>,
+}
+
+
+impl<T: ?Sized> Record<T>
 {
-    fn clone(&self) -> Self
+    /// Whether values can be moved into their record, which takes a thin pointer to point to them.
+    const THIN: bool = mem::size_of::<*mut T>() == mem::size_of::<*mut u8>();
+
+    /// Allocates the record of `value`, moving a boxed value into it if `inline` is set.
+    fn new(value: HeapPtr<T>, inline: bool) -> *mut Self
+    {
+        if !(inline && Self::THIN && value.holder() == Holder::Box)
+        {
+            return Box::into_raw(Box::new(Self::holding(value)));
+        }
+
+        let (layout, offset) = Self::layout(value.as_ptr());
+        let record = unsafe { alloc::alloc(layout) }.cast::<Self>();
+
+        if record.is_null()
+        {
+            alloc::handle_alloc_error(layout);
+        }
+
+        unsafe {
+            let inner = record.cast::<u8>().add(offset);
+            ptr::copy_nonoverlapping(value.as_ptr().cast::<u8>(), inner, mem::size_of_val(value.as_ref()));
+
+            // The value has been moved, so only its box is freed
+            drop(Box::from_raw(value.as_ptr() as *mut ManuallyDrop<T>));
+            record.write(Self::holding(HeapPtr::from_ptr(Self::thin(inner), Holder::Record)));
+        }
+
+        record
+    }
+
+    fn holding(value: HeapPtr<T>) -> Self
     {
         Self {
-            ptr: self.ptr,
-            guard_ptr: self.guard_ptr,
-            domain: self.domain,
-            keep_count: self.keep_count,
+            value,
+            version: 0,
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Returns the layout of a record holding `value` inside, together with the offset of the value.
+    fn layout(value: *mut T) -> (Layout, usize)
+    {
+        let (layout, offset) = Layout::new::<Self>()
+            .extend(Layout::for_value(unsafe { &*value }))
+            .expect("records of values that fit into memory fit as well");
+
+        (layout.pad_to_align(), offset)
+    }
+
+    /// Turns the thin pointer `ptr` into a pointer to the value, which has no metadata.
+    ///
+    /// # Safety
+    /// Pointers to `T` must be thin, see `Record::THIN`.
+    #[inline]
+    unsafe fn thin(ptr: *mut u8) -> *mut T
+    {
+        unsafe { mem::transmute_copy(&ptr) }
+    }
+
+    #[inline]
+    pub fn value(&self) -> HeapPtr<T>
+    {
+        self.value
+    }
+
+    #[inline]
+    pub fn version(&self) -> u64
+    {
+        self.version
+    }
+
+    /// Frees `record` and returns its value, moving it into a box of its own if it is held by the record.
+    ///
+    /// # Safety
+    /// `record` must not be referenced anymore.
+    pub unsafe fn into_value(record: *mut Self) -> HeapPtr<T>
+    {
+        let value = unsafe { (*record).value };
+
+        if value.holder() != Holder::Record
+        {
+            return unsafe { Box::from_raw(record) }.value;
+        }
+
+        let layout = Layout::for_value(value.as_ref());
+
+        // Boxes of zero sized values do not allocate, their pointer only has to be aligned
+        let boxed = match layout.size()
+        {
+            0 => ptr::without_provenance_mut(layout.align()),
+            _ => unsafe { alloc::alloc(layout) },
+        };
+
+        if boxed.is_null()
+        {
+            alloc::handle_alloc_error(layout);
         }
+
+        unsafe {
+            ptr::copy_nonoverlapping(value.as_ptr().cast::<u8>(), boxed, layout.size());
+            alloc::dealloc(record.cast(), Self::layout(value.as_ptr()).0);
+
+            HeapPtr::from_ptr(Self::thin(boxed), Holder::Box)
+        }
+    }
+
+    /// Frees `record` together with its value, or releases its arc.
+    ///
+    /// # Safety
+    /// `record` must not be referenced anymore.
+    pub unsafe fn free(record: *mut Self)
+    {
+        let value = unsafe { (*record).value };
+
+        if value.holder() != Holder::Record
+        {
+            return unsafe { Self::into_value(record).free() };
+        }
+
+        let layout = Self::layout(value.as_ptr()).0;
+
+        unsafe {
+            ptr::drop_in_place(value.as_ptr());
+            alloc::dealloc(record.cast(), layout);
+        }
+    }
+}
+
+
+impl<T> Record<T>
+{
+    /// Frees `record` and moves its value out.
+    ///
+    /// # Safety
+    /// `record` must not be referenced anymore and its value must not be shared, see `HeapPtr::is_unique()`.
+    pub unsafe fn into_inner(record: *mut Self) -> T
+    {
+        let value = unsafe { (*record).value };
+
+        if value.holder() != Holder::Record
+        {
+            return unsafe { Self::into_value(record).into_inner() };
+        }
+
+        unsafe {
+            let inner = ptr::read(value.as_ptr());
+            alloc::dealloc(record.cast(), Self::layout(value.as_ptr()).0);
+
+            inner
+        }
+    }
+}
+
+
+/// The guard nodes of a tracked atomic together with its current value and values waiting to be freed.
+///
+/// The domain keeps the allocation of its tracked atomic alive for as long as there are guards left.
+pub struct Domain<T: ?Sized>
+{
+    /// The record of the current value of the tracked atomic, null once it is dead.
+    current: AtomicPtr<Record<T>>,
+    nodes: GuardNodes<T>,
+
+    /// The amount of guards registered in this domain, plus one for its tracked atomic.
+    refs: AtomicUsize,
+
+    /// The top of the stack of records of values that have been replaced, but may still be guarded.
+    retired: AtomicPtr<Record<T>>,
+
+    /// Held while walking the guard nodes, so that only one thread reclaims at a time and no segment is trimmed.
+    reclaiming: Mutex<()>,
+
+    /// The amount of reclamations requested since the last one started.
+    requests: AtomicUsize,
+
+    /// The parts of the domain that most tracked atomics never use, null until one of them is needed.
+    extras: AtomicPtr<Extras<T>>,
+}
+
+
+/// The parts of a domain that are only allocated for keeps holding arcs, keeps with a reclaimer
+/// and keeps that are watched, so that every other keep does not pay for them.
+struct Extras<T: ?Sized>
+{
+    /// Whether every value is moved into an arc when it is stored.
+    arcs: bool,
+
+    /// Receives boxed values that are no longer referenced, instead of dropping them.
+    reclaimer: Option<Reclaimer<T>>,
+
+    /// Notified whenever a value is replaced, waited on together with the lock of the wakers.
+    changed: Condvar,
+
+    /// Wakers of futures waiting for a new value, by the id of their future.
+    wakers: Mutex<Vec<(u64, Waker)>>,
+
+    /// The amount of threads and futures waiting for a new value.
+    watchers: AtomicUsize,
+}
+
+
+impl<T: ?Sized> Extras<T>
+{
+    fn new(arcs: bool, reclaimer: Option<Reclaimer<T>>) -> Self
+    {
+        Self {
+            arcs,
+            reclaimer,
+            changed: Condvar::new(),
+            wakers: Mutex::new(Vec::new()),
+            watchers: AtomicUsize::new(0),
+        }
+    }
+}
+
+
+impl<T: ?Sized> Domain<T>
+{
+    fn new(value: HeapPtr<T>, arcs: bool, reclaimer: Option<Reclaimer<T>>) -> Self
+    {
+        let record = Record::new(value, reclaimer.is_none());
+
+        let extras = match arcs || reclaimer.is_some()
+        {
+            true => Box::into_raw(Box::new(Extras::new(arcs, reclaimer))),
+            false => ptr::null_mut(),
+        };
+
+        Self {
+            current: AtomicPtr::new(record),
+            nodes: GuardNodes::new(),
+            refs: AtomicUsize::new(1),
+            retired: AtomicPtr::new(ptr::null_mut()),
+            reclaiming: Mutex::new(()),
+            requests: AtomicUsize::new(0),
+            extras: AtomicPtr::new(extras),
+        }
+    }
+
+    /// Returns the extras of this domain, if they have been allocated.
+    #[inline]
+    fn extras(&self) -> Option<&Extras<T>>
+    {
+        unsafe { self.extras.load(Ordering::SeqCst).as_ref() }
+    }
+
+    /// Returns the extras of this domain, allocating them if nobody did so far.
+    fn extras_or_init(&self) -> &Extras<T>
+    {
+        if let Some(extras) = self.extras()
+        {
+            return extras;
+        }
+
+        let new = Box::into_raw(Box::new(Extras::new(false, None)));
+
+        match self
+            .extras
+            .compare_exchange(ptr::null_mut(), new, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Ok(_) => unsafe { &*new },
+            Err(actual) =>
+            {
+                drop(unsafe { Box::from_raw(new) });
+                unsafe { &*actual }
+            }
+        }
+    }
+
+    /// Returns the extras of a domain that has been watched, which allocated them.
+    #[inline]
+    fn watched(&self) -> &Extras<T>
+    {
+        self.extras().expect("watchers allocate the extras")
+    }
+
+    #[inline]
+    fn arcs(&self) -> bool
+    {
+        self.extras().is_some_and(|extras| extras.arcs)
+    }
+
+    #[inline]
+    fn reclaimer(&self) -> Option<&Reclaimer<T>>
+    {
+        self.extras()?.reclaimer.as_ref()
+    }
+
+    /// Sets up the first guard nodes of the domain at `this`, which must not move anymore.
+    unsafe fn init(this: *mut Self)
+    {
+        unsafe { GuardNodes::init(&raw mut (*this).nodes, this) };
+    }
+
+    /// Registers a guard node for `record`.
+    fn register(&self, record: *mut Record<T>) -> *mut GuardNode<T>
+    {
+        self.refs.fetch_add(1, Ordering::SeqCst);
+
+        let node = self.nodes.acquire(self);
+        node.value.store(record.cast(), Ordering::SeqCst);
+
+        node as *const GuardNode<T> as *mut _
+    }
+
+    /// Frees `record` once it is no longer guarded.
+    ///
+    /// `record` must have been replaced already, so that it cannot be guarded by new readers.
+    fn retire(&self, record: *mut Record<T>)
+    {
+        self.push_retired(&[record]);
+        self.reclaim();
+    }
+
+    /// Wakes every thread and future waiting for a new value.
+    ///
+    /// Watchers check for a new value while holding the lock of the wakers,
+    /// so they either see a value that was stored before it is taken here or are notified.
+    fn notify(&self)
+    {
+        // Watchers allocate the extras before they check the value, so none of them can be waiting without them
+        let Some(extras) = self.extras()
+        else
+        {
+            return;
+        };
+
+        if extras.watchers.load(Ordering::SeqCst) != 0
+        {
+            // Woken futures register again if they keep waiting
+            let wakers = mem::take(&mut *extras.wakers.lock());
+            extras.changed.notify_all();
+
+            for (_, waker) in wakers
+            {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Returns the amount of guard nodes guarding `record`.
+    ///
+    /// Nodes may only be walked while reclaiming is locked.
+    fn guard_count(&self, record: *mut Record<T>) -> usize
+    {
+        self.nodes
+            .iter()
+            .filter(|node| node.value.load(Ordering::SeqCst) == record.cast())
+            .count()
+    }
+
+    /// Requests to free every retired value that is not guarded anymore.
+    ///
+    /// A reclamation walks every node, so requests are collected until there are enough of them to pay for it,
+    /// see `RECLAIM_RATIO`. Only one thread reclaims at a time, without making others wait.
+    /// A thread that finds another one reclaiming leaves its request to that thread,
+    /// which checks for requests again once it is done.
+    fn reclaim(&self)
+    {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+
+        // Either the reclaiming thread sees the request after unlocking, or this thread sees it unlocked
+        atomic::fence(Ordering::SeqCst);
+
+        while self.requests.load(Ordering::SeqCst) >= self.reclaim_threshold()
+        {
+            let Some(reclaiming) = self.reclaiming.try_lock()
+            else
+            {
+                return;
+            };
+
+            // Records retired after this are left to the next request
+            self.requests.store(0, Ordering::SeqCst);
+            let retired = self.take_retired();
+
+            // Values can also be protected by scoped reads, which do not register a guard.
+            // Refreshed guards protect a value before their node guards it, so protections are collected first.
+            let mut guarded = hazard::protected();
+
+            guarded.extend(
+                self.nodes
+                    .iter()
+                    .map(|node| node.value.load(Ordering::SeqCst))
+                    .filter(|value| !value.is_null()),
+            );
+
+            let (reclaimable, still_guarded): (Vec<_>, Vec<_>) = retired
+                .into_iter()
+                .partition(|record| !guarded.contains(&record.cast()));
+
+            self.push_retired(&still_guarded);
+
+            // Free outside of the lock, dropping values may drop guards of this domain.
+            drop(reclaiming);
+
+            for record in reclaimable
+            {
+                unsafe { self.free(record) };
+            }
+
+            atomic::fence(Ordering::SeqCst);
+        }
+    }
+
+    /// Returns the amount of requests a reclamation waits for.
+    ///
+    /// Once at most one guard is left, requests are served right away, so that values of idle keeps do not linger.
+    #[inline]
+    fn reclaim_threshold(&self) -> usize
+    {
+        match self.refs.load(Ordering::SeqCst) <= 2
+        {
+            true => 1,
+            false => (self.nodes.count() / RECLAIM_RATIO).max(1),
+        }
+    }
+
+    /// Takes every record off the stack of retired records.
+    fn take_retired(&self) -> Vec<*mut Record<T>>
+    {
+        let mut records = Vec::new();
+        let mut record = self.retired.swap(ptr::null_mut(), Ordering::SeqCst);
+
+        while !record.is_null()
+        {
+            records.push(record);
+            record = unsafe { &*record }.next.load(Ordering::SeqCst);
+        }
+
+        records
+    }
+
+    /// Pushes `records` onto the stack of retired records at once.
+    fn push_retired(&self, records: &[*mut Record<T>])
+    {
+        let (Some(&first), Some(&last)) = (records.first(), records.last())
+        else
+        {
+            return;
+        };
+
+        for pair in records.windows(2)
+        {
+            unsafe { &*pair[0] }.next.store(pair[1], Ordering::SeqCst);
+        }
+
+        let mut top = self.retired.load(Ordering::SeqCst);
+
+        loop
+        {
+            unsafe { &*last }.next.store(top, Ordering::SeqCst);
+
+            match self
+                .retired
+                .compare_exchange(top, first, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => break,
+                Err(actual) => top = actual,
+            }
+        }
+    }
+
+    /// Frees `record` and its value, or hands the value to the reclaimer if there is one.
+    ///
+    /// Values held by arcs only release their arc, as they might still be shared outside of the keep.
+    ///
+    /// # Safety
+    /// `record` must not be referenced anymore.
+    unsafe fn free(&self, record: *mut Record<T>)
+    {
+        match self.reclaimer()
+        {
+            Some(_) => unsafe { self.free_value(Record::into_value(record)) },
+            None => unsafe { Record::free(record) },
+        }
+    }
+
+    /// Frees `value`, or hands it to the reclaimer if there is one, see `Domain::free(..)`.
+    ///
+    /// # Safety
+    /// `value` must not be referenced anymore.
+    unsafe fn free_value(&self, value: HeapPtr<T>)
+    {
+        match self.reclaimer()
+        {
+            Some(reclaimer) if !value.is_arc() => reclaimer(unsafe { Box::from_raw(value.as_ptr()) }),
+            _ => unsafe { value.free() },
+        }
+    }
+
+    /// Releases the reference of an anchor returned by `TrackedAtomic::word(..)`.
+    unsafe fn release_anchor(this: *mut ())
+    {
+        unsafe { Self::release(this as *mut Self) };
+    }
+
+    /// Releases one reference to the domain, freeing its tracked atomic if it was the last one.
+    unsafe fn release(this: *mut Self)
+    {
+        if 1 < unsafe { &*this }.refs.fetch_sub(1, Ordering::SeqCst)
+        {
+            return;
+        }
+
+        // Nobody is left to guard anything, so every retired value can be freed.
+        let domain = unsafe { &*this };
+
+        for record in domain.take_retired()
+        {
+            unsafe { domain.free(record) };
+        }
+
+        // The domain is the first field of its tracked atomic, so both start at the same address
+        unsafe { HeapPtr::from_ptr(this as *mut TrackedAtomic<T>, Holder::Box).free() };
+    }
+}
+
+
+impl<T: ?Sized> Drop for Domain<T>
+{
+    fn drop(&mut self)
+    {
+        let extras = *self.extras.get_mut();
+
+        if !extras.is_null()
+        {
+            drop(unsafe { Box::from_raw(extras) });
+        }
+    }
+}
+
+
+/// The amount of guard nodes in the first segment, every further segment is twice as large.
+const FIRST_SEGMENT: usize = 2;
+
+/// The amount of segments, which covers every index a node can have.
+///
+/// Their nodes are only allocated on demand, the guards needed to fill all of them would not fit into memory.
+const SEGMENTS: usize = 31;
+
+/// The amount of guard nodes a reclamation may walk per request, so that requests take constant time on average.
+const RECLAIM_RATIO: usize = 8;
+
+
+/// The guard nodes of a domain, which are registered and unregistered in constant time.
+///
+/// Nodes live in segments that double in size. Every segment keeps its own stack of free nodes
+/// and nodes are taken from the lowest segment that has a free one, so that higher segments run empty
+/// once a spike of guards is over. Empty segments are trimmed, once the guards left fit into half
+/// of the segments below them. The nodes of the first segment live inside the domain and are never trimmed.
+struct GuardNodes<T: ?Sized>
+{
+    first: Segment<T>,
+    first_nodes: [MaybeUninit<GuardNode<T>>; FIRST_SEGMENT],
+
+    /// Every segment after the first one, allocated once the first segment is full for the first time.
+    rest: AtomicPtr<[Segment<T>; SEGMENTS - 1]>,
+
+    /// A bit for every segment whose nodes are allocated.
+    allocated: AtomicU32,
+}
+
+
+impl<T: ?Sized> GuardNodes<T>
+{
+    fn new() -> Self
+    {
+        Self {
+            first: Segment::new(),
+            first_nodes: [const { MaybeUninit::uninit() }; FIRST_SEGMENT],
+            rest: AtomicPtr::new(ptr::null_mut()),
+            allocated: AtomicU32::new(0),
+        }
+    }
+
+    /// Sets up the nodes of the first segment.
+    ///
+    /// # Safety
+    /// `this` must be the guard nodes of `domain`, which must not move anymore.
+    unsafe fn init(this: *mut Self, domain: *mut Domain<T>)
+    {
+        unsafe {
+            let nodes = &raw mut (*this).first_nodes as *mut GuardNode<T>;
+
+            for index in 0..FIRST_SEGMENT
+            {
+                nodes.add(index).write(GuardNode::new(domain, index));
+            }
+
+            (*this)
+                .first
+                .adopt(nodes, 0, &(*this).allocated)
+                .expect("the first segment has no nodes yet");
+        }
+    }
+
+    /// Takes a free node from the lowest segment that has one.
+    fn acquire(&self, domain: &Domain<T>) -> &GuardNode<T>
+    {
+        loop
+        {
+            for number in 0..SEGMENTS
+            {
+                let segment = self.segment_or_allocate(number);
+
+                if let Some(node) = segment.try_acquire(number, domain, &self.allocated)
+                {
+                    return node;
+                }
+            }
+
+            // Every segment is full, which takes more guards than memory can hold,
+            // so one of them must be on its way out
+            thread::yield_now();
+        }
+    }
+
+    /// Returns `node` to the free nodes and trims empty segments, if the guards left fit into the lower segments.
+    ///
+    /// `node` may be freed once this returns.
+    fn release(&self, node: &GuardNode<T>, domain: &Domain<T>)
+    {
+        let segment = self
+            .segment(node.segment())
+            .expect("the segment of a node in use is allocated");
+        segment.release(node);
+
+        loop
+        {
+            let allocated = self.allocated.load(Ordering::SeqCst);
+
+            if allocated < 2
+            {
+                return;
+            }
+
+            let top = allocated.ilog2() as usize;
+            let guards = domain.refs.load(Ordering::SeqCst).saturating_sub(1);
+
+            if 2 * guards > Segment::<T>::first_index(top)
+            {
+                return;
+            }
+
+            // Trimming must not race with walks over the nodes, which happen while reclaiming is locked.
+            // Trimming is only worth it if nobody has to wait for it, the next release tries again otherwise.
+            let Some(_reclaiming) = domain.reclaiming.try_lock()
+            else
+            {
+                return;
+            };
+
+            if !self.segment(top).is_some_and(|segment| segment.try_trim(top, &self.allocated))
+            {
+                return;
+            }
+        }
+    }
+
+    /// Returns every node of every allocated segment.
+    ///
+    /// Reclaiming must be locked while walking the nodes, so that no segment is trimmed.
+    fn iter(&self) -> impl Iterator<Item = &GuardNode<T>>
+    {
+        (0..SEGMENTS)
+            .filter_map(|number| self.segment(number).map(|segment| (number, segment)))
+            .flat_map(|(number, segment)| segment.nodes(number))
+    }
+
+    /// Returns the amount of allocated nodes.
+    ///
+    /// Segment `number` holds `FIRST_SEGMENT << number` nodes, so the bits of the allocated segments add up to them.
+    #[inline]
+    fn count(&self) -> usize
+    {
+        FIRST_SEGMENT * self.allocated.load(Ordering::SeqCst) as usize
+    }
+
+    #[inline]
+    fn segment(&self, number: usize) -> Option<&Segment<T>>
+    {
+        match number
+        {
+            0 => Some(&self.first),
+            _ => unsafe { self.rest.load(Ordering::SeqCst).as_ref() }.map(|rest| &rest[number - 1]),
+        }
+    }
+
+    fn segment_or_allocate(&self, number: usize) -> &Segment<T>
+    {
+        if let Some(segment) = self.segment(number)
+        {
+            return segment;
+        }
+
+        let rest = Box::into_raw(Box::new([const { Segment::new() }; SEGMENTS - 1]));
+
+        let installed = self
+            .rest
+            .compare_exchange(ptr::null_mut(), rest, Ordering::SeqCst, Ordering::SeqCst);
+
+        if installed.is_err()
+        {
+            drop(unsafe { Box::from_raw(rest) });
+        }
+
+        self.segment(number).expect("the segments have just been allocated")
+    }
+}
+
+
+impl<T: ?Sized> Drop for GuardNodes<T>
+{
+    fn drop(&mut self)
+    {
+        // The first segment is part of the domain
+        for number in 1..SEGMENTS
+        {
+            if let Some(segment) = self.segment(number)
+            {
+                unsafe { segment.free(number) };
+            }
+        }
+
+        let rest = self.rest.load(Ordering::SeqCst);
+
+        if !rest.is_null()
+        {
+            drop(unsafe { Box::from_raw(rest) });
+        }
+    }
+}
+
+
+/// A segment of guard nodes, which are allocated on demand and freed once the segment is trimmed.
+struct Segment<T: ?Sized>
+{
+    nodes: AtomicPtr<GuardNode<T>>,
+
+    /// The top of the stack of free nodes as `offset + 1`, or 0 if it is empty,
+    /// tagged with a counter in the upper half that changes on every push and pop to prevent ABA.
+    free: AtomicU64,
+
+    /// The amount of nodes in use, plus threads about to take a free node.
+    in_use: AtomicU32,
+
+    /// Set while the segment is being trimmed, so that no thread takes a node from it.
+    closed: AtomicBool,
+}
+
+
+impl<T: ?Sized> Segment<T>
+{
+    const fn new() -> Self
+    {
+        Self {
+            nodes: AtomicPtr::new(ptr::null_mut()),
+            free: AtomicU64::new(0),
+            in_use: AtomicU32::new(0),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Takes a free node from this segment, allocating its nodes if they are not yet.
+    fn try_acquire(
+        &self,
+        number: usize,
+        domain: &Domain<T>,
+        allocated: &AtomicU32,
+    ) -> Option<&GuardNode<T>>
+    {
+        // Skip full segments without touching their counters
+        let full = !self.nodes.load(Ordering::SeqCst).is_null()
+            && self.free.load(Ordering::SeqCst) as u32 == 0;
+
+        if full
+        {
+            return None;
+        }
+
+        // Announce the use first, so that a concurrent trim either sees it or has already closed the segment
+        self.in_use.fetch_add(1, Ordering::SeqCst);
+
+        if self.closed.load(Ordering::SeqCst)
+        {
+            self.in_use.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+
+        let mut nodes = self.nodes.load(Ordering::SeqCst);
+
+        if nodes.is_null()
+        {
+            nodes = self.allocate(number, domain, allocated);
+        }
+
+        let mut free = self.free.load(Ordering::SeqCst);
+
+        while free as u32 != 0
+        {
+            let node = unsafe { &*nodes.add(free as u32 as usize - 1) };
+            let next = node.next_free.load(Ordering::SeqCst);
+            let popped = (free >> 32).wrapping_add(1) << 32 | next as u64;
+
+            match self
+                .free
+                .compare_exchange(free, popped, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return Some(node),
+                Err(actual) => free = actual,
+            }
+        }
+
+        self.in_use.fetch_sub(1, Ordering::SeqCst);
+        None
+    }
+
+    /// Pushes `node` onto the stack of free nodes, after which it may be freed.
+    fn release(&self, node: &GuardNode<T>)
+    {
+        self.push(node);
+        self.in_use.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn push(&self, node: &GuardNode<T>)
+    {
+        let mut free = self.free.load(Ordering::SeqCst);
+
+        loop
+        {
+            node.next_free.store(free as u32, Ordering::SeqCst);
+            let pushed = (free >> 32).wrapping_add(1) << 32 | (node.offset() + 1) as u64;
+
+            match self
+                .free
+                .compare_exchange(free, pushed, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return,
+                Err(actual) => free = actual,
+            }
+        }
+    }
+
+    /// Frees the nodes of this segment, if none of them is in use.
+    ///
+    /// Returns whether the segment has been trimmed.
+    fn try_trim(&self, number: usize, allocated: &AtomicU32) -> bool
+    {
+        self.closed.store(true, Ordering::SeqCst);
+
+        let trimmed = self.in_use.load(Ordering::SeqCst) == 0;
+
+        if trimmed
+        {
+            allocated.fetch_and(!(1 << number), Ordering::SeqCst);
+            self.free.store(0, Ordering::SeqCst);
+            unsafe { self.free(number) };
+        }
+
+        self.closed.store(false, Ordering::SeqCst);
+        trimmed
+    }
+
+    /// Allocates the nodes of this segment and pushes them onto its stack, unless another thread was faster.
+    #[cold]
+    fn allocate(
+        &self,
+        number: usize,
+        domain: &Domain<T>,
+        allocated: &AtomicU32,
+    ) -> *mut GuardNode<T>
+    {
+        let domain = domain as *const Domain<T> as *mut Domain<T>;
+        let nodes: Box<[_]> = (0..Self::len(number))
+            .map(|offset| GuardNode::new(domain, Self::first_index(number) + offset))
+            .collect();
+        let nodes = Box::into_raw(nodes) as *mut GuardNode<T>;
+
+        match self.adopt(nodes, number, allocated)
+        {
+            Ok(()) => nodes,
+            Err(actual) =>
+            {
+                unsafe { Self::free_nodes(nodes, number) };
+                actual
+            }
+        }
+    }
+
+    /// Makes `nodes` the nodes of this segment and pushes them onto its stack,
+    /// unless it already has nodes, which are returned instead.
+    fn adopt(
+        &self,
+        nodes: *mut GuardNode<T>,
+        number: usize,
+        allocated: &AtomicU32,
+    ) -> Result<(), *mut GuardNode<T>>
+    {
+        self.nodes
+            .compare_exchange(ptr::null_mut(), nodes, Ordering::SeqCst, Ordering::SeqCst)?;
+
+        for offset in 0..Self::len(number)
+        {
+            self.push(unsafe { &*nodes.add(offset) });
+        }
+
+        allocated.fetch_or(1 << number, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Returns the nodes of this segment, or none if they are not allocated.
+    fn nodes(&self, number: usize) -> &[GuardNode<T>]
+    {
+        let nodes = self.nodes.load(Ordering::SeqCst);
+
+        match nodes.is_null()
+        {
+            true => &[],
+            false => unsafe { std::slice::from_raw_parts(nodes, Self::len(number)) },
+        }
+    }
+
+    /// Frees the nodes of this segment.
+    ///
+    /// # Safety
+    /// None of the nodes may be used anymore and this must not be the first segment, whose nodes live in the domain.
+    unsafe fn free(&self, number: usize)
+    {
+        let nodes = self.nodes.swap(ptr::null_mut(), Ordering::SeqCst);
+
+        if !nodes.is_null()
+        {
+            unsafe { Self::free_nodes(nodes, number) };
+        }
+    }
+
+    unsafe fn free_nodes(nodes: *mut GuardNode<T>, number: usize)
+    {
+        let nodes = ptr::slice_from_raw_parts_mut(nodes, Self::len(number));
+        drop(unsafe { Box::from_raw(nodes) });
+    }
+
+    #[inline]
+    fn len(number: usize) -> usize
+    {
+        FIRST_SEGMENT << number
+    }
+
+    /// Returns the amount of nodes in every segment before segment `number`.
+    #[inline]
+    fn first_index(number: usize) -> usize
+    {
+        FIRST_SEGMENT * ((1 << number) - 1)
+    }
+}
+
+
+pub struct GuardNode<T: ?Sized>
+{
+    domain: *mut Domain<T>,
+
+    /// The address of the record of the guarded value, null while this node is free.
+    value: AtomicPtr<()>,
+
+    /// The position of this node across all segments.
+    index: u32,
+
+    /// The next node on the stack of free nodes of its segment as `offset + 1`, or 0 if there is none.
+    next_free: AtomicU32,
+}
+
+
+unsafe impl<T: ?Sized + Send + Sync> Send for GuardNode<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for GuardNode<T> {}
+
+
+impl<T: ?Sized> GuardNode<T>
+{
+    fn new(domain: *mut Domain<T>, index: usize) -> Self
+    {
+        Self {
+            domain,
+            value: AtomicPtr::new(ptr::null_mut()),
+            index: index as u32,
+            next_free: AtomicU32::new(0),
+        }
+    }
+
+    /// Returns the number of the segment holding this node.
+    #[inline]
+    fn segment(&self) -> usize
+    {
+        (self.index as usize / FIRST_SEGMENT + 1).ilog2() as usize
+    }
+
+    /// Returns the position of this node within its segment.
+    #[inline]
+    fn offset(&self) -> u32
+    {
+        self.index - Segment::<T>::first_index(self.segment()) as u32
+    }
+
+    /// Registers `value` again inside the domain of this node, for a guard that already guards it.
+    ///
+    /// Every guard needs its own registration, so that each of them can unregister independently.
+    pub fn register_again(&self, record: *mut Record<T>) -> *mut Self
+    {
+        unsafe { &*self.domain }.register(record)
+    }
+
+    /// Returns the tracked atomic of the domain of this node.
+    #[inline]
+    pub fn tracked_atomic(&self) -> &TrackedAtomic<T>
+    {
+        // The domain is the first field of its tracked atomic, so both start at the same address
+        unsafe { &*(self.domain as *const TrackedAtomic<T>) }
+    }
+
+    /// Takes `record` out of the domain and unregisters this node,
+    /// if `record` has been retired and is guarded by this node only.
+    ///
+    /// On success the calling guard is unregistered and must not be dropped anymore.
+    pub fn try_take(&self, record: *mut Record<T>) -> bool
+    {
+        let domain = unsafe { &*self.domain };
+        let reclaiming = domain.reclaiming.lock();
+        let mut retired = domain.take_retired();
+
+        // Retired values cannot be read anymore, so no other guard can appear while the retired records are taken,
+        // except for short lived registrations of readers that find out the value has been replaced.
+        let taken = retired
+            .iter()
+            .position(|retired| *retired == record)
+            .filter(|_| {
+                !hazard::is_protected(record.cast())
+                    && domain.guard_count(record) == 1
+                    && unsafe { (*record).value.is_unique() }
+            })
+            .map(|position| retired.swap_remove(position))
+            .is_some();
+
+        domain.push_retired(&retired);
+        drop(reclaiming);
+
+        if taken
+        {
+            self.unregister(record);
+        }
+
+        taken
+    }
+
+    /// Unregisters this guard from the domain if its guarding `record`
+    pub fn unregister(&self, record: *mut Record<T>) -> bool
+    {
+        if record.is_null()
+        {
+            return false;
+        }
+
+        let result = self.value.compare_exchange(
+            record.cast(),
+            ptr::null_mut(),
+            Ordering::SeqCst,
+            Ordering::Relaxed,
+        );
+
+        // return false if this node did not unregister successfully
+        if result.is_err()
+        {
+            return false;
+        }
+
+        // This node must not be touched anymore once it is released, as its segment might be trimmed.
+        let domain_ptr = self.domain;
+        let domain = unsafe { &*domain_ptr };
+        domain.nodes.release(self, domain);
+
+        // Now that value is no longer guarded by this node, it might be reclaimable.
+        // The current value cannot have been retired before this node stopped guarding it,
+        // so its retirement will notice that it is unguarded.
+        if mcas::load(&domain.current) != record
+        {
+            domain.reclaim();
+        }
+
+        unsafe { Domain::release(domain_ptr) };
+
+        true
     }
 }
@@ -0,0 +1,323 @@
+use crate::{
+    Guard, HeapPtr, Heaped, Keep,
+    mcas::{self, Anchor, Word},
+    tracked_atomic::{Record, TrackedAtomic},
+};
+use std::ptr;
+
+
+/// The reads and writes of a transaction, see `transaction(..)`.
+pub struct Transaction<'a>
+{
+    reads: Vec<Box<dyn Access + 'a>>,
+    writes: Vec<Box<dyn Access + 'a>>,
+}
+
+
+impl<'a> Transaction<'a>
+{
+    fn new() -> Self
+    {
+        Self {
+            reads: Vec::new(),
+            writes: Vec::new(),
+        }
+    }
+
+    /// Reads the current value of `keep`.
+    ///
+    /// The transaction is only committed, if `keep` still holds this value at that time.
+    /// Writes of this transaction are not visible to its reads.
+    pub fn read<T: ?Sized + 'a>(&mut self, keep: &'a Keep<T>) -> Guard<T>
+    {
+        let guard = keep.read();
+        self.reads.push(Box::new(Read { guard: guard.clone() }));
+
+        guard
+    }
+
+    /// Stores `value` in `keep` once the transaction is committed.
+    pub fn write<T: ?Sized + 'a>(&mut self, keep: &'a Keep<T>, value: impl Heaped<T> + 'a)
+    {
+        self.writes.push(Box::new(Write {
+            keep,
+            value: Some(value.heap_ptr()),
+            old: None,
+            record: ptr::null_mut(),
+        }));
+    }
+
+    /// Replaces the values of every written keep and checks that every read value is still current,
+    /// all in a single multi-word compare-and-swap.
+    ///
+    /// Returns whether the transaction has been committed.
+    fn commit(mut self) -> bool
+    {
+        for write in &mut self.writes
+        {
+            write.prepare();
+        }
+
+        // The last write to a tracked atomic decides its value, earlier ones are dropped with the transaction
+        let writes: Vec<_> = (0..self.writes.len())
+            .filter(|&i| {
+                let tracked_atomic = self.writes[i].tracked_atomic();
+                !self.writes[i + 1..].iter().any(|later| later.tracked_atomic() == tracked_atomic)
+            })
+            .collect();
+
+        let mut accesses: Vec<&dyn Access> = writes.iter().map(|&i| &*self.writes[i]).collect();
+
+        // Reads of written tracked atomics are checked by their writes, as those replace the same value
+        for read in &self.reads
+        {
+            match accesses.iter().find(|access| access.tracked_atomic() == read.tracked_atomic())
+            {
+                Some(access) if access.old() != read.old() => return false,
+                Some(_) => {}
+                None => accesses.push(&**read),
+            }
+        }
+
+        let (words, anchors) = accesses.iter().map(|access| access.word()).unzip();
+
+        if !mcas::mcas(words, anchors)
+        {
+            return false;
+        }
+
+        for i in writes
+        {
+            self.writes[i].commit();
+        }
+
+        true
+    }
+}
+
+
+/// A keep read or written by a transaction, with the type of its values erased.
+trait Access
+{
+    /// Reads the value to replace and allocates the record of the new one, if this is a write.
+    fn prepare(&mut self) {}
+
+    /// Returns the address of the tracked atomic accessed, which must have been prepared already.
+    fn tracked_atomic(&self) -> *const ();
+
+    /// Returns the record of the value the tracked atomic has to hold for the transaction to commit.
+    fn old(&self) -> *mut ();
+
+    /// Returns the word of the tracked atomic and its anchor, see `TrackedAtomic::word(..)`.
+    fn word(&self) -> (Word, Anchor);
+
+    /// Finishes the access, once the transaction has been committed.
+    fn commit(&mut self) {}
+}
+
+
+/// A value read by a transaction, which must still be current once it is committed.
+struct Read<T: ?Sized>
+{
+    guard: Guard<T>,
+}
+
+
+impl<T: ?Sized> Read<T>
+{
+    fn tracked(&self) -> &TrackedAtomic<T>
+    {
+        unsafe { &*self.guard.guard_node() }.tracked_atomic()
+    }
+}
+
+
+impl<T: ?Sized> Access for Read<T>
+{
+    fn tracked_atomic(&self) -> *const ()
+    {
+        (self.tracked() as *const TrackedAtomic<T>).cast()
+    }
+
+    fn old(&self) -> *mut ()
+    {
+        self.guard.record().cast()
+    }
+
+    fn word(&self) -> (Word, Anchor)
+    {
+        self.tracked().word(self.guard.record(), self.guard.record())
+    }
+}
+
+
+/// A value written by a transaction, which replaces the value of its keep once it is committed.
+struct Write<'a, T: ?Sized>
+{
+    keep: &'a Keep<T>,
+
+    /// The value to write, until its record is allocated.
+    value: Option<HeapPtr<T>>,
+
+    /// The value to replace, once the write is prepared.
+    old: Option<Guard<T>>,
+
+    /// The record of the new value, until the transaction is committed.
+    record: *mut Record<T>,
+}
+
+
+impl<T: ?Sized> Write<'_, T>
+{
+    fn old_guard(&self) -> &Guard<T>
+    {
+        self.old.as_ref().expect("writes are prepared before they are committed")
+    }
+
+    fn tracked(&self) -> &TrackedAtomic<T>
+    {
+        unsafe { &*self.old_guard().guard_node() }.tracked_atomic()
+    }
+}
+
+
+impl<T: ?Sized> Access for Write<'_, T>
+{
+    fn prepare(&mut self)
+    {
+        let old = self.keep.read();
+
+        if let Some(value) = self.value.take()
+        {
+            self.record = unsafe { &*old.guard_node() }.tracked_atomic().prepare(value, &old);
+        }
+
+        self.old = Some(old);
+    }
+
+    fn tracked_atomic(&self) -> *const ()
+    {
+        (self.tracked() as *const TrackedAtomic<T>).cast()
+    }
+
+    fn old(&self) -> *mut ()
+    {
+        self.old_guard().record().cast()
+    }
+
+    fn word(&self) -> (Word, Anchor)
+    {
+        self.tracked().word(self.old_guard().record(), self.record)
+    }
+
+    fn commit(&mut self)
+    {
+        self.tracked().committed(self.old_guard().record());
+        self.record = ptr::null_mut();
+    }
+}
+
+
+impl<T: ?Sized> Drop for Write<'_, T>
+{
+    fn drop(&mut self)
+    {
+        // Writes that have not been committed were never visible to any other thread,
+        // but their values belong to the reclaimer of the keep like every other value
+        if let Some(value) = self.value.take()
+        {
+            self.keep.discard(value);
+        }
+
+        if !self.record.is_null()
+        {
+            self.tracked().discard(unsafe { Record::into_value(self.record) });
+        }
+    }
+}
+
+
+/// Runs `f` and commits its writes to several keeps at once.
+///
+/// Snapshots taken with `snapshot(..)` see either all or none of the writes of a transaction.
+/// If another thread changed any value `f` has read or is about to replace before the transaction is committed,
+/// its writes are discarded and `f` is run again. Transactions apply to the tracked atomics the keeps reference
+/// at the time, so they are not ordered against `Keep::swap_with(..)`.
+pub fn transaction<'a, R>(mut f: impl FnMut(&mut Transaction<'a>) -> R) -> R
+{
+    loop
+    {
+        let mut transaction = Transaction::new();
+        let result = f(&mut transaction);
+
+        if transaction.commit()
+        {
+            break result;
+        }
+    }
+}
+
+
+/// Reads several keeps at once, so that their values have all been current at the same time.
+///
+/// `keeps` is a tuple of keep references, like `(&a, &b)`, and a tuple of their guards is returned.
+/// Snapshots are retried until no value changed while reading. Every value is current from its read
+/// until it is checked again, so all of them have been current once the last one has been read.
+pub fn snapshot<S: Snapshot>(keeps: S) -> S::Guards
+{
+    loop
+    {
+        let guards = keeps.read();
+
+        if keeps.is_current(&guards)
+        {
+            break guards;
+        }
+    }
+}
+
+
+/// Keeps that can be read at once by `snapshot(..)`.
+pub trait Snapshot
+{
+    type Guards;
+
+    /// Reads every keep, one after another.
+    fn read(&self) -> Self::Guards;
+
+    /// Returns whether every keep still holds the value guarded by `guards`.
+    ///
+    /// Values are compared by their records, as values replacing them might be allocated at the same address.
+    fn is_current(&self, guards: &Self::Guards) -> bool;
+}
+
+
+macro_rules! impl_snapshot {
+    ($($t:ident $keep:ident $guard:ident),+) => {
+        impl<$($t: ?Sized),+> Snapshot for ($(&Keep<$t>,)+)
+        {
+            type Guards = ($(Guard<$t>,)+);
+
+            fn read(&self) -> Self::Guards
+            {
+                let ($($keep,)+) = self;
+                ($($keep.read(),)+)
+            }
+
+            fn is_current(&self, guards: &Self::Guards) -> bool
+            {
+                let ($($keep,)+) = self;
+                let ($($guard,)+) = guards;
+                $($keep.holds($guard))&&+
+            }
+        }
+    };
+}
+
+
+impl_snapshot!(A a guard_a);
+impl_snapshot!(A a guard_a, B b guard_b);
+impl_snapshot!(A a guard_a, B b guard_b, C c guard_c);
+impl_snapshot!(A a guard_a, B b guard_b, C c guard_c, D d guard_d);
+impl_snapshot!(A a guard_a, B b guard_b, C c guard_c, D d guard_d, E e guard_e);
+impl_snapshot!(A a guard_a, B b guard_b, C c guard_c, D d guard_d, E e guard_e, F f guard_f);
@@ -0,0 +1,117 @@
+use crate::{Guard, Keep};
+use std::{
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+
+/// The id of the next future that waits for a new value.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+
+/// Waits for new values of a keep, created by `Keep::subscribe()`.
+///
+/// The watcher remembers the last value it has seen, so values stored in between two waits are not missed,
+/// though only the newest of them is returned.
+pub struct Watcher<T: ?Sized>
+{
+    keep: Keep<T>,
+    seen: Guard<T>,
+}
+
+
+impl<T: ?Sized> Watcher<T>
+{
+    pub(crate) fn new(keep: Keep<T>) -> Self
+    {
+        let seen = keep.read();
+        Self { keep, seen }
+    }
+
+    /// Blocks until a new value is stored by `write`, `swap` or `exchange` and returns it.
+    pub fn changed(&mut self) -> Guard<T>
+    {
+        self.seen = self.keep.wait_for_change(&self.seen);
+        self.seen.clone()
+    }
+
+    /// Like `Watcher::changed()`, but gives up after `timeout` and returns `None` then.
+    pub fn changed_timeout(&mut self, timeout: Duration) -> Option<Guard<T>>
+    {
+        self.seen = self.keep.wait_for_change_timeout(&self.seen, timeout)?;
+        Some(self.seen.clone())
+    }
+
+    /// Returns the value this watcher has seen last.
+    #[inline]
+    pub fn seen(&self) -> &Guard<T>
+    {
+        &self.seen
+    }
+}
+
+
+/// A future resolving to the next value of a keep, created by `Keep::changed()`.
+pub(crate) struct Changed<T: ?Sized>
+{
+    keep: Keep<T>,
+    seen: Guard<T>,
+
+    /// The id the waker of this future is registered under, once it waits.
+    id: Option<u64>,
+}
+
+
+impl<T: ?Sized> Changed<T>
+{
+    pub(crate) fn new(keep: Keep<T>) -> Self
+    {
+        let seen = keep.read();
+
+        Self {
+            keep,
+            seen,
+            id: None,
+        }
+    }
+}
+
+
+impl<T: ?Sized> Future for Changed<T>
+{
+    type Output = Guard<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Guard<T>>
+    {
+        let this = self.get_mut();
+
+        let id = *this.id.get_or_insert_with(|| {
+            this.keep.watch();
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        });
+
+        if !this.keep.register_waker(id, &this.seen, cx.waker())
+        {
+            return Poll::Pending;
+        }
+
+        this.keep.unwatch(id);
+        this.id = None;
+
+        Poll::Ready(this.keep.read())
+    }
+}
+
+
+impl<T: ?Sized> Drop for Changed<T>
+{
+    fn drop(&mut self)
+    {
+        if let Some(id) = self.id
+        {
+            self.keep.unwatch(id);
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use crate::{Keep, tracked_atomic::TrackedAtomic};
+
+
+/// A non-owning handle to the tracked atomic of a keep, created by `Keep::downgrade()`.
+///
+/// Weak keeps do not keep values alive, once the last keep is dropped its value is reclaimed
+/// and every weak keep fails to upgrade.
+pub struct WeakKeep<T: ?Sized>
+{
+    tracked_atomic: *mut TrackedAtomic<T>,
+}
+
+
+unsafe impl<T: ?Sized + Send + Sync> Send for WeakKeep<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for WeakKeep<T> {}
+
+
+impl<T: ?Sized> WeakKeep<T>
+{
+    /// Creates a weak keep of `tracked_atomic`, which has already been registered for it.
+    #[inline]
+    pub(crate) fn from_registered(tracked_atomic: *mut TrackedAtomic<T>) -> Self
+    {
+        Self { tracked_atomic }
+    }
+
+    /// Returns a keep of the tracked atomic, or `None` if every keep of it has already been dropped.
+    pub fn upgrade(&self) -> Option<Keep<T>>
+    {
+        self.tracked()
+            .try_register_keep()
+            .then(|| Keep::from_registered(self.tracked_atomic))
+    }
+
+    #[inline]
+    fn tracked(&self) -> &TrackedAtomic<T>
+    {
+        // This weak keep holds a weak reference to its tracked atomic, so it cannot have been freed.
+        unsafe { &*self.tracked_atomic }
+    }
+}
+
+
+impl<T: ?Sized> Clone for WeakKeep<T>
+{
+    fn clone(&self) -> Self
+    {
+        self.tracked().register_weak();
+        Self::from_registered(self.tracked_atomic)
+    }
+}
+
+
+impl<T: ?Sized> Drop for WeakKeep<T>
+{
+    fn drop(&mut self)
+    {
+        self.tracked().unregister_weak();
+    }
+}
@@ -1,4 +1,27 @@
 use keep::*;
+use std::{
+    any::Any,
+    borrow::Cow,
+    cell::Cell,
+    pin::pin,
+    rc::Rc,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+
+/// Records whether it has been dropped.
+struct DropFlag(Rc<Cell<bool>>);
+
+
+impl Drop for DropFlag
+{
+    fn drop(&mut self)
+    {
+        self.0.set(true);
+    }
+}
 
 
 #[test]
@@ -167,3 +190,686 @@ fn exchange()
     assert_eq!(39, *ok);
     assert_eq!("???", *err);
 }
+
+
+#[test]
+fn versions_count_stores()
+{
+    let keep = Keep::new(39);
+    let first = keep.read();
+
+    keep.write(14);
+    let old = keep.swap(10);
+    let _ = keep.exchange(&keep.read(), 1);
+
+    assert_eq!(0, first.version());
+    assert_eq!(0, first.clone().version());
+    assert_eq!(1, old.version());
+    assert_eq!(3, keep.read().version());
+    assert_eq!(3, keep.version());
+}
+
+
+#[test]
+fn exchange_fails_for_new_values_at_the_same_address()
+{
+    // Every box of a zero sized value has the same address
+    let keep = Keep::new(());
+    let stale = keep.read();
+    keep.write(());
+
+    let actual = keep.exchange(&stale, ()).unwrap_err();
+
+    assert_eq!(1, actual.version());
+    assert!(keep.exchange(&actual, ()).is_ok());
+    assert_eq!(2, keep.version());
+}
+
+
+#[test]
+fn same_value_tells_stores_apart()
+{
+    let keep = Keep::new(());
+    let first = keep.read();
+
+    assert!(Guard::same_value(&first, &first.clone()));
+    assert!(Guard::same_value(&first, &keep.read()));
+
+    keep.write(());
+
+    // Both values share an address, but they have been stored separately
+    assert!(!Guard::same_value(&first, &keep.read()));
+}
+
+
+#[test]
+fn unsized_values()
+{
+    let text: Keep<str> = Keep::new(String::from("Miku"));
+    let old = text.swap(Box::<str>::from("Briar"));
+
+    assert_eq!("Miku", &*old);
+    assert_eq!("Briar", &*text.read());
+    assert_eq!(5, text.read_with(str::len));
+
+    let numbers: Keep<[u32]> = Keep::new(vec![3, 9]);
+    let current = numbers.read();
+    numbers.exchange(&current, vec![1, 4]).unwrap();
+
+    assert_eq!([1, 4], *numbers.read());
+    assert_eq!(3, *Guard::map(current, |numbers| &numbers[0]));
+}
+
+
+#[test]
+fn trait_objects()
+{
+    let dropped = Rc::new(Cell::new(false));
+    let flag: Box<dyn Any> = Box::new(DropFlag(dropped.clone()));
+    let keep: Keep<dyn Any> = Keep::new(flag);
+
+    assert!(keep.read().is::<DropFlag>());
+
+    keep.write(Box::new(39) as Box<dyn Any>);
+
+    assert!(dropped.get());
+    assert_eq!(Some(&39), keep.read().downcast_ref::<i32>());
+}
+
+
+#[test]
+fn arc_values()
+{
+    let shared = Arc::new(String::from("Miku"));
+    let keep = Keep::from_arc(shared.clone());
+    let arc = keep.read().to_arc().unwrap();
+
+    // The value is shared with the arc instead of being copied
+    assert!(Arc::ptr_eq(&shared, &arc));
+
+    keep.write(String::from("Briar"));
+
+    assert_eq!(2, Arc::strong_count(&shared));
+    assert_eq!("Briar", *keep.read().to_arc().unwrap());
+    assert!(keep.try_unwrap().is_ok());
+
+    let keep = Keep::new(39);
+    assert_eq!(None, keep.read().to_arc());
+
+    // Other keeps hold arcs like any other value
+    let keep = Keep::new(shared.clone());
+    assert!(Arc::ptr_eq(&shared, &keep.read()));
+    assert_eq!(None, keep.read().to_arc());
+}
+
+
+#[test]
+fn shared_arcs_cannot_be_unwrapped()
+{
+    let shared = Arc::new(39);
+    let keep = Keep::from_arc(shared.clone());
+
+    let keep = keep.try_unwrap().unwrap_err();
+    let guard = keep.read();
+    drop(keep);
+
+    let guard = guard.try_unwrap().unwrap_err();
+    drop(shared);
+
+    assert_eq!(Ok(39), guard.try_unwrap());
+}
+
+
+#[test]
+fn cow_and_arc_slices()
+{
+    let keep: Keep<str> = Keep::new(Cow::Borrowed("Miku"));
+    assert_eq!("Miku", &*keep.read());
+
+    keep.write(Cow::<str>::Owned(String::from("Briar")));
+    assert_eq!("Briar", &*keep.read());
+
+    let keep = Keep::<[i32]>::from_arc(Arc::from([39, 40]));
+    assert_eq!(&[39, 40], &*keep.read().to_arc().unwrap());
+}
+
+
+#[test]
+fn reclaimers_receive_released_values()
+{
+    let pool = Arc::new(Mutex::new(Vec::new()));
+    let keep = Keep::with_reclaimer(39, {
+        let pool = pool.clone();
+        move |value| pool.lock().unwrap().push(value)
+    });
+
+    let guard = keep.read();
+    keep.write(40);
+
+    // The replaced value is still guarded
+    assert!(pool.lock().unwrap().is_empty());
+
+    drop(guard);
+    assert_eq!(vec![Box::new(39)], *pool.lock().unwrap());
+
+    keep.write(41);
+    drop(keep);
+
+    // The last value is reclaimed once the keep is gone
+    assert_eq!(vec![Box::new(39), Box::new(40), Box::new(41)], *pool.lock().unwrap());
+}
+
+
+#[test]
+fn reclaimers_receive_rejected_values()
+{
+    let pool = Arc::new(Mutex::new(Vec::new()));
+    let keep = Keep::with_reclaimer(39, {
+        let pool = pool.clone();
+        move |value| pool.lock().unwrap().push(value)
+    });
+
+    let guard = keep.read();
+    keep.write(40);
+
+    assert_eq!(40, *keep.exchange(&guard, 41).unwrap_err());
+    assert_eq!(vec![Box::new(41)], *pool.lock().unwrap());
+}
+
+
+#[test]
+fn reclaimers_receive_values_that_lost_an_update()
+{
+    let pool = Arc::new(Mutex::new(Vec::new()));
+    let keep = Keep::with_reclaimer(39, {
+        let pool = pool.clone();
+        move |value| pool.lock().unwrap().push(value)
+    });
+
+    let mut runs = 0;
+
+    let old = keep.fetch_update(|value| {
+        runs += 1;
+
+        // The first result loses against this write
+        if runs == 1
+        {
+            keep.write(100);
+        }
+
+        Some(value + 1)
+    });
+
+    assert_eq!(100, *old.unwrap());
+    assert!(pool.lock().unwrap().contains(&Box::new(40)));
+}
+
+
+#[test]
+fn reclaimers_receive_values_of_aborted_transactions()
+{
+    let pool = Arc::new(Mutex::new(Vec::new()));
+    let keep = Keep::with_reclaimer(0, {
+        let pool = pool.clone();
+        move |value| pool.lock().unwrap().push(value)
+    });
+
+    let mut runs = 0;
+
+    transaction(|tx| {
+        runs += 1;
+        let value = tx.read(&keep);
+
+        // The first run is aborted, as the value it has read changes
+        if runs == 1
+        {
+            keep.write(39);
+        }
+
+        tx.write(&keep, *value + 100);
+    });
+
+    assert_eq!(139, *keep.read());
+    assert!(pool.lock().unwrap().contains(&Box::new(100)));
+}
+#[test]
+fn rcu()
+{
+    let keep = Keep::new(39);
+
+    let old = keep.rcu(|val| val + 1);
+
+    assert_eq!(39, *old);
+    assert_eq!(40, *keep.read());
+}
+
+
+#[test]
+fn fetch_update()
+{
+    let keep = Keep::new(39);
+
+    let old = keep.fetch_update(|val| (*val < 40).then_some(val + 1)).unwrap();
+    let current = keep.fetch_update(|val| (*val < 40).then_some(val + 1)).unwrap_err();
+
+    assert_eq!(39, *old);
+    assert_eq!(40, *current);
+    assert_eq!(40, *keep.read());
+}
+
+
+#[test]
+fn weak_keep()
+{
+    let keep = Keep::new(39);
+    let weak = keep.downgrade();
+
+    let upgraded = weak.upgrade().unwrap();
+    assert_eq!(39, *upgraded.read());
+
+    keep.write(14);
+    assert_eq!(14, *upgraded.read());
+
+    drop(keep);
+    assert!(weak.clone().upgrade().is_some());
+
+    drop(upgraded);
+    assert!(weak.upgrade().is_none());
+    assert!(weak.clone().upgrade().is_none());
+}
+
+
+#[test]
+fn weak_keep_does_not_keep_value_alive()
+{
+    let dropped = Rc::new(Cell::new(false));
+    let keep = Keep::new(DropFlag(dropped.clone()));
+    let weak = keep.downgrade();
+
+    drop(keep);
+    assert!(weak.upgrade().is_none());
+    drop(weak);
+
+    assert!(dropped.get());
+}
+
+
+#[test]
+fn map_guard()
+{
+    struct Config
+    {
+        name: String,
+        port: Option<u16>,
+    }
+
+    let keep = Keep::new(Config {
+        name: String::from("plug"),
+        port: None,
+    });
+
+    let name = Guard::map(keep.read(), |config| config.name.as_str());
+    keep.write(Config {
+        name: String::from("map"),
+        port: Some(39),
+    });
+
+    assert_eq!("plug", &*name);
+    assert_eq!("pl", &*MappedGuard::map(name, |name| &name[..2]));
+
+    let port = Guard::filter_map(keep.read(), |config| config.port.as_ref());
+    assert!(port.is_ok_and(|port| *port == 39));
+
+    let suffix = Guard::try_map(keep.read(), |config| config.name.get(10..).ok_or("too short"));
+    assert!(suffix.is_err());
+}
+
+
+#[test]
+fn cloned_guard_outlives_original()
+{
+    let dropped = Rc::new(Cell::new(false));
+    let keep = Keep::new(DropFlag(dropped.clone()));
+
+    let guard = keep.read();
+    let clone = guard.clone();
+    keep.write(DropFlag(Rc::default()));
+
+    drop(guard);
+    assert!(!dropped.get());
+    assert!(!clone.0.get());
+
+    drop(clone);
+    assert!(dropped.get());
+}
+
+
+#[test]
+fn original_guard_outlives_clone()
+{
+    let dropped = Rc::new(Cell::new(false));
+    let keep = Keep::new(DropFlag(dropped.clone()));
+
+    let guard = keep.read();
+    let clone = guard.clone();
+    keep.write(DropFlag(Rc::default()));
+
+    drop(clone);
+    assert!(!dropped.get());
+    assert!(!guard.0.get());
+
+    drop(guard);
+    assert!(dropped.get());
+}
+
+
+#[test]
+fn clones_of_clones_outlive_keep()
+{
+    let dropped = Rc::new(Cell::new(false));
+    let keep = Keep::new(DropFlag(dropped.clone()));
+
+    let guard = keep.read();
+    let clone = guard.clone();
+    let clone_of_clone = clone.clone();
+
+    drop(keep);
+    drop(clone);
+    drop(guard);
+    assert!(!dropped.get());
+    assert!(!clone_of_clone.0.get());
+
+    drop(clone_of_clone);
+    assert!(dropped.get());
+}
+
+
+#[test]
+fn keep_try_unwrap()
+{
+    let keep = Keep::<String>::new(String::from("Briar"));
+    let clone = keep.clone();
+
+    let keep = keep.try_unwrap().unwrap_err();
+    drop(clone);
+
+    let guard = keep.read();
+    let keep = keep.try_unwrap().unwrap_err();
+    drop(guard);
+
+    let weak = keep.downgrade();
+    assert_eq!("Briar", keep.try_unwrap().ok().unwrap());
+    assert!(weak.upgrade().is_none());
+}
+
+
+#[test]
+fn guard_try_unwrap()
+{
+    let keep = Keep::<String>::new(String::from("Briar"));
+
+    let guard = keep.read();
+    let guard = guard.try_unwrap().unwrap_err();
+
+    keep.write(String::from("Miku"));
+    let clone = guard.clone();
+    let guard = guard.try_unwrap().unwrap_err();
+    drop(clone);
+
+    assert_eq!("Briar", guard.try_unwrap().unwrap());
+    assert_eq!("Miku", *keep.read());
+
+    let guard = keep.read();
+    drop(keep);
+    assert_eq!("Miku", guard.try_unwrap().unwrap());
+}
+
+
+#[test]
+fn values_of_any_alignment_move_through_their_records()
+{
+    #[repr(align(64))]
+    #[derive(Debug, PartialEq)]
+    struct Aligned(u8);
+
+    let keep = Keep::new(Aligned(39));
+    let stale = keep.read();
+    keep.write(Aligned(40));
+
+    assert_eq!(0, &*stale as *const Aligned as usize % 64);
+    assert_eq!(Aligned(40), *keep.exchange(&stale, Aligned(41)).unwrap_err());
+    assert_eq!(Ok(Aligned(39)), stale.try_unwrap());
+    assert_eq!(Some(Aligned(40)), keep.try_unwrap().ok());
+
+    let dropped = Rc::new(Cell::new(false));
+    let keep = Keep::new(DropFlag(dropped.clone()));
+
+    keep.write(DropFlag(Rc::new(Cell::new(false))));
+    assert!(dropped.get());
+}
+
+
+#[test]
+fn read_with()
+{
+    let dropped = Rc::new(Cell::new(false));
+    let keep = Keep::new(DropFlag(dropped.clone()));
+
+    keep.read_with(|value| {
+        keep.write(DropFlag(Rc::default()));
+
+        assert!(!dropped.get());
+        assert!(!value.0.get());
+    });
+
+    assert!(dropped.get());
+    assert_eq!(39, Keep::new(39).read_with(|value| *value));
+}
+
+
+#[test]
+fn pin()
+{
+    let keep = Keep::new(39);
+    let mut pinned = keep.pin();
+
+    assert_eq!(39, *pinned);
+    assert!(!pinned.refresh());
+
+    keep.write(14);
+    let guard = pinned.guard();
+    assert_eq!(39, *pinned);
+
+    assert!(pinned.refresh());
+    assert_eq!(14, *pinned);
+    assert_eq!(39, *guard);
+}
+
+
+#[test]
+fn refreshed_pin_releases_value()
+{
+    let dropped = Rc::new(Cell::new(false));
+    let keep = Keep::new(DropFlag(dropped.clone()));
+    let mut pinned = keep.pin();
+
+    keep.write(DropFlag(Rc::default()));
+    assert!(!dropped.get());
+
+    pinned.refresh();
+    assert!(dropped.get());
+}
+
+
+#[test]
+fn many_guards_reuse_nodes()
+{
+    let keep = Keep::new(0);
+    let mut guards: Vec<_> = (0..1000).map(|_| keep.read()).collect();
+
+    // Every other guard is dropped and read again, reusing the nodes that were freed
+    for round in 1..10
+    {
+        keep.write(round);
+
+        for guard in guards.iter_mut().step_by(2)
+        {
+            *guard = keep.read();
+        }
+
+        assert!(guards.iter().step_by(2).all(|guard| **guard == round));
+        assert!(guards.iter().skip(1).step_by(2).all(|guard| **guard == 0));
+    }
+}
+
+
+#[test]
+fn nodes_are_trimmed_after_spikes()
+{
+    let keep = Keep::new(39);
+    drop(keep.read());
+    let idle = keep.node_count();
+
+    let guards: Vec<_> = (0..1000).map(|_| keep.read()).collect();
+    let spike = keep.node_count();
+    assert!(spike >= 1000);
+
+    // Dropping a few guards does not free any nodes yet, they are reused by the next guards
+    let mut guards = guards.into_iter().skip(10).collect::<Vec<_>>();
+    guards.extend((0..10).map(|_| keep.read()));
+    assert_eq!(spike, keep.node_count());
+
+    drop(guards);
+    assert_eq!(idle, keep.node_count());
+    assert_eq!(39, *keep.read());
+}
+
+
+#[test]
+fn transaction_writes_every_keep()
+{
+    let keep_a = Keep::new(39);
+    let keep_b = Keep::<String>::new(String::from("Miku"));
+
+    let length = transaction(|tx| {
+        let a = tx.read(&keep_a);
+        let b = tx.read(&keep_b);
+
+        tx.write(&keep_a, *a + 1);
+        tx.write(&keep_b, format!("{}{}", *b, *a));
+
+        // Writes only become visible once the transaction is committed
+        assert_eq!(39, *keep_a.read());
+        b.len()
+    });
+
+    assert_eq!(4, length);
+    assert_eq!(40, *keep_a.read());
+    assert_eq!("Miku39", *keep_b.read());
+}
+
+
+#[test]
+fn transaction_retries_on_conflict()
+{
+    let keep = Keep::new(0);
+    let mut runs = 0;
+
+    transaction(|tx| {
+        runs += 1;
+        let value = tx.read(&keep);
+
+        // Another write to a value that has been read makes the transaction run again
+        if runs == 1
+        {
+            keep.write(39);
+        }
+
+        tx.write(&keep, *value + 1);
+    });
+
+    assert_eq!(2, runs);
+    assert_eq!(40, *keep.read());
+}
+
+
+#[test]
+fn transaction_keeps_the_last_write()
+{
+    let keep = Keep::new(DropFlag(Rc::new(Cell::new(false))));
+    let clone = keep.clone();
+    let overwritten = Rc::new(Cell::new(false));
+    let last = Rc::new(Cell::new(false));
+
+    // Both keeps reference the same tracked atomic, so the second write replaces the first one
+    transaction(|tx| {
+        tx.write(&keep, DropFlag(overwritten.clone()));
+        tx.write(&clone, DropFlag(last.clone()));
+    });
+
+    assert!(overwritten.get());
+    assert!(!last.get());
+    assert!(Rc::ptr_eq(&last, &keep.read().0));
+}
+
+
+#[test]
+fn snapshot_reads_every_keep()
+{
+    let keep_a = Keep::new(39);
+    let keep_b = Keep::new("Miku");
+
+    let (a, b) = snapshot((&keep_a, &keep_b));
+    keep_a.write(2);
+
+    assert_eq!(39, *a);
+    assert_eq!("Miku", *b);
+    assert_eq!(2, *snapshot((&keep_a,)).0);
+}
+
+
+#[test]
+fn watcher_sees_writes()
+{
+    let keep = Keep::new(39);
+    let mut watcher = keep.subscribe();
+
+    assert!(watcher.changed_timeout(Duration::from_millis(10)).is_none());
+
+    keep.write(2);
+    keep.swap(3);
+
+    // Values stored in between two waits are not missed, but only the newest one is returned
+    assert_eq!(3, *watcher.changed());
+    assert_eq!(3, **watcher.seen());
+    assert!(watcher.changed_timeout(Duration::from_millis(10)).is_none());
+
+    let current = keep.read();
+    assert!(keep.exchange(&current, 4).is_ok());
+    assert_eq!(4, *keep.wait_for_change(&current));
+    assert_eq!(4, *watcher.changed());
+}
+
+
+#[test]
+fn changed_future_resolves_on_writes()
+{
+    let keep = Keep::new(39);
+    let mut context = Context::from_waker(Waker::noop());
+
+    let mut changed = pin!(keep.changed());
+    assert!(changed.as_mut().poll(&mut context).is_pending());
+    assert!(changed.as_mut().poll(&mut context).is_pending());
+
+    keep.write(2);
+
+    match changed.as_mut().poll(&mut context)
+    {
+        Poll::Ready(value) => assert_eq!(2, *value),
+        Poll::Pending => panic!("the future missed a write"),
+    }
+
+    // Futures only wait for values stored after they were created
+    let mut changed = pin!(keep.changed());
+    assert!(changed.as_mut().poll(&mut context).is_pending());
+}
@@ -0,0 +1,1027 @@
+use keep::*;
+use std::{
+    pin::pin,
+    sync::{
+        Arc, Barrier,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    task::{Context, Poll, Wake, Waker},
+    thread,
+};
+
+
+const THREADS: usize = 8;
+const ITERATIONS: usize = 2000;
+
+
+#[derive(Default)]
+struct Counters
+{
+    created: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+
+/// Counts its creations and drops, to find leaked or double freed values.
+struct Counted
+{
+    value: usize,
+    counters: Arc<Counters>,
+}
+
+
+impl Counted
+{
+    fn new(value: usize, counters: &Arc<Counters>) -> Self
+    {
+        counters.created.fetch_add(1, Ordering::SeqCst);
+
+        Self {
+            value,
+            counters: counters.clone(),
+        }
+    }
+}
+
+
+impl Drop for Counted
+{
+    fn drop(&mut self)
+    {
+        self.counters.dropped.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+
+/// Wakes a thread blocked in `block_on(..)`.
+struct ThreadWaker(thread::Thread);
+
+
+impl Wake for ThreadWaker
+{
+    fn wake(self: Arc<Self>)
+    {
+        self.0.unpark();
+    }
+}
+
+
+/// Polls `future` on this thread until it is ready, parking the thread in between.
+fn block_on<F: Future>(future: F) -> F::Output
+{
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+
+    loop
+    {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context)
+        {
+            break output;
+        }
+
+        thread::park();
+    }
+}
+
+
+#[test]
+fn send_and_sync()
+{
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    assert_send_sync::<Keep<String>>();
+    assert_send_sync::<Guard<String>>();
+    assert_send_sync::<KeepMarker<String>>();
+    assert_send_sync::<WeakKeep<String>>();
+    assert_send_sync::<Watcher<String>>();
+    assert_send_sync::<Keep<str>>();
+    assert_send_sync::<Guard<dyn Fn() + Send + Sync>>();
+}
+
+
+#[test]
+fn readers_see_consistent_values()
+{
+    let keep = Arc::new(Keep::new((0usize, 0usize)));
+
+    let readers: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let keep = keep.clone();
+
+            thread::spawn(move || {
+                let mut last = 0;
+
+                for _ in 0..ITERATIONS
+                {
+                    let guard = keep.read();
+                    assert_eq!(guard.0 * 2, guard.1);
+                    assert!(last <= guard.0, "a reader went back in time");
+                    last = guard.0;
+                }
+            })
+        })
+        .collect();
+
+    for i in 1..=ITERATIONS
+    {
+        keep.write((i, i * 2));
+    }
+
+    for reader in readers
+    {
+        reader.join().unwrap();
+    }
+
+    assert_eq!(ITERATIONS, keep.read().0);
+}
+
+
+#[test]
+fn readers_see_the_version_of_their_value()
+{
+    let keep = Arc::new(Keep::new(0usize));
+
+    let readers: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let keep = keep.clone();
+
+            thread::spawn(move || {
+                let mut pinned = keep.pin();
+
+                for _ in 0..ITERATIONS
+                {
+                    let guard = keep.read();
+                    assert_eq!(*guard as u64, guard.version());
+
+                    pinned.refresh();
+                    assert_eq!(*pinned as u64, pinned.guard().version());
+                }
+            })
+        })
+        .collect();
+
+    for i in 1..=ITERATIONS
+    {
+        keep.write(i);
+    }
+
+    for reader in readers
+    {
+        reader.join().unwrap();
+    }
+
+    assert_eq!(ITERATIONS as u64, keep.version());
+}
+
+
+#[test]
+fn readers_see_consistent_slices()
+{
+    let keep: Arc<Keep<[usize]>> = Arc::new(Keep::new(vec![1]));
+
+    let readers: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let keep = keep.clone();
+
+            thread::spawn(move || {
+                for _ in 0..ITERATIONS
+                {
+                    let guard = keep.read();
+                    assert!(guard.iter().all(|&value| value == guard.len()));
+
+                    keep.read_with(|values| assert!(values.iter().all(|&value| value == values.len())));
+                }
+            })
+        })
+        .collect();
+
+    for i in 1..=ITERATIONS
+    {
+        let len = i % 64 + 1;
+        keep.write(vec![len; len]);
+    }
+
+    for reader in readers
+    {
+        reader.join().unwrap();
+    }
+}
+
+
+#[test]
+fn scoped_reads_see_consistent_values()
+{
+    let counters = Arc::new(Counters::default());
+    let keep = Arc::new(Keep::new(Counted::new(0, &counters)));
+    let barrier = Arc::new(Barrier::new(THREADS + 1));
+
+    let readers: Vec<_> = (0..THREADS)
+        .map(|id| {
+            let keep = keep.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                barrier.wait();
+
+                let mut pinned = keep.pin();
+                let mut last = 0;
+
+                for _ in 0..ITERATIONS
+                {
+                    // Values only ever grow, so neither read can observe an older value
+                    let value = keep.read_with(|counted| counted.value);
+                    assert!(last <= value);
+
+                    if id % 2 == 0
+                    {
+                        pinned.refresh();
+                        assert!(value <= pinned.value);
+                    }
+
+                    last = value;
+                }
+            })
+        })
+        .collect();
+
+    barrier.wait();
+
+    for i in 1..=ITERATIONS
+    {
+        keep.write(Counted::new(i, &counters));
+    }
+
+    for reader in readers
+    {
+        reader.join().unwrap();
+    }
+
+    let created = counters.created.load(Ordering::SeqCst);
+    let guard = Arc::into_inner(keep).unwrap().read();
+
+    assert_eq!(created - 1, counters.dropped.load(Ordering::SeqCst));
+    drop(guard);
+    assert_eq!(created, counters.dropped.load(Ordering::SeqCst));
+}
+
+
+#[test]
+fn concurrent_exchange_loses_no_updates()
+{
+    let keep = Arc::new(Keep::new(0usize));
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    let writers: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let keep = keep.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                barrier.wait();
+
+                for _ in 0..ITERATIONS
+                {
+                    let mut current = keep.read();
+
+                    while let Err(actual) = keep.exchange(&current, *current + 1)
+                    {
+                        current = actual;
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for writer in writers
+    {
+        writer.join().unwrap();
+    }
+
+    assert_eq!(THREADS * ITERATIONS, *keep.read());
+}
+
+
+#[test]
+fn concurrent_rcu_loses_no_updates()
+{
+    let counters = Arc::new(Counters::default());
+    let keep = Arc::new(Keep::new(Counted::new(0, &counters)));
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    let writers: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let keep = keep.clone();
+            let counters = counters.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                barrier.wait();
+
+                for _ in 0..ITERATIONS
+                {
+                    keep.rcu(|current| Counted::new(current.value + 1, &counters));
+                }
+            })
+        })
+        .collect();
+
+    for writer in writers
+    {
+        writer.join().unwrap();
+    }
+
+    let created = counters.created.load(Ordering::SeqCst);
+    let guard = Arc::into_inner(keep).unwrap().read();
+
+    // Values that lost a race were dropped right away, everything else once it was replaced
+    assert_eq!(THREADS * ITERATIONS, guard.value);
+    assert_eq!(created - 1, counters.dropped.load(Ordering::SeqCst));
+    drop(guard);
+    assert_eq!(created, counters.dropped.load(Ordering::SeqCst));
+}
+
+
+#[test]
+fn every_value_is_dropped_exactly_once()
+{
+    let counters = Arc::new(Counters::default());
+    let keep = Arc::new(Keep::new(Counted::new(0, &counters)));
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|id| {
+            let keep = keep.clone();
+            let counters = counters.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                barrier.wait();
+
+                for i in 0..ITERATIONS
+                {
+                    match i % 3
+                    {
+                        0 => keep.write(Counted::new(id, &counters)),
+                        1 => assert!(keep.swap(Counted::new(id, &counters)).value < THREADS),
+                        _ => assert!(keep.read().value < THREADS),
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for thread in threads
+    {
+        thread.join().unwrap();
+    }
+
+    let created = counters.created.load(Ordering::SeqCst);
+    let guard = Arc::into_inner(keep).unwrap().read();
+
+    assert_eq!(created - 1, counters.dropped.load(Ordering::SeqCst));
+    drop(guard);
+    assert_eq!(created, counters.dropped.load(Ordering::SeqCst));
+}
+
+
+#[test]
+fn arcs_outlive_keeps_across_threads()
+{
+    let counters = Arc::new(Counters::default());
+    let keep = Arc::new(Keep::from_arc(Arc::new(Counted::new(0, &counters))));
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|id| {
+            let keep = keep.clone();
+            let counters = counters.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                barrier.wait();
+
+                (0..ITERATIONS)
+                    .map(|i| match i % 2
+                    {
+                        0 => keep.swap(Counted::new(id, &counters)).to_arc().unwrap(),
+                        _ => keep.read().to_arc().unwrap(),
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+
+    let arcs: Vec<_> = threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect();
+    drop(keep);
+
+    assert!(arcs.iter().all(|arc| arc.value < THREADS));
+    assert!(counters.dropped.load(Ordering::SeqCst) < counters.created.load(Ordering::SeqCst));
+
+    drop(arcs);
+    assert_eq!(counters.created.load(Ordering::SeqCst), counters.dropped.load(Ordering::SeqCst));
+}
+
+
+#[test]
+fn every_value_is_reclaimed_exactly_once()
+{
+    let counters = Arc::new(Counters::default());
+    let reclaimed = Arc::new(AtomicUsize::new(0));
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    let keep = Arc::new(Keep::with_reclaimer(Counted::new(0, &counters), {
+        let reclaimed = reclaimed.clone();
+        move |value: Box<Counted>| {
+            assert!(value.value < THREADS);
+            reclaimed.fetch_add(1, Ordering::SeqCst);
+        }
+    }));
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|id| {
+            let keep = keep.clone();
+            let counters = counters.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                barrier.wait();
+
+                for i in 0..ITERATIONS
+                {
+                    match i % 2
+                    {
+                        0 => keep.write(Counted::new(id, &counters)),
+                        _ => assert!(keep.read().value < THREADS),
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for thread in threads
+    {
+        thread.join().unwrap();
+    }
+
+    drop(keep);
+
+    let created = counters.created.load(Ordering::SeqCst);
+    assert_eq!(created, reclaimed.load(Ordering::SeqCst));
+    assert_eq!(created, counters.dropped.load(Ordering::SeqCst));
+}
+
+
+#[test]
+fn guards_outlive_keeps_on_other_threads()
+{
+    let keep = Keep::<String>::new(String::from("Briar"));
+    let guards: Vec<_> = (0..THREADS).map(|_| keep.read()).collect();
+
+    keep.write(String::from("Miku"));
+    drop(keep);
+
+    let threads: Vec<_> = guards
+        .into_iter()
+        .map(|guard| thread::spawn(move || assert_eq!("Briar", *guard)))
+        .collect();
+
+    for thread in threads
+    {
+        thread.join().unwrap();
+    }
+}
+
+
+#[test]
+fn clones_and_swaps_across_threads()
+{
+    let keep_a = Arc::new(Keep::new(39usize));
+    let keep_b = Arc::new(Keep::new(39usize));
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|id| {
+            let keep_a = keep_a.clone();
+            let keep_b = keep_b.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                barrier.wait();
+
+                for _ in 0..ITERATIONS
+                {
+                    if id == 0
+                    {
+                        keep_a.swap_with(&keep_b);
+                        continue;
+                    }
+
+                    let clone = keep_a.clone();
+                    assert_eq!(39, *clone.read());
+                    assert_eq!(39, *keep_b.read());
+                }
+            })
+        })
+        .collect();
+
+    for thread in threads
+    {
+        thread.join().unwrap();
+    }
+}
+
+
+#[test]
+fn overlapping_swaps_lose_and_duplicate_nothing()
+{
+    const KEEPS: usize = 4;
+
+    let counters = Arc::new(Counters::default());
+    let keeps: Arc<Vec<_>> = Arc::new(
+        (0..KEEPS)
+            .map(|value| Keep::new(Counted::new(value, &counters)))
+            .collect(),
+    );
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|id| {
+            let keeps = keeps.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                let mut random = (id as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+                barrier.wait();
+
+                // Enough iterations for the threads to be preempted in the middle of swaps
+                for _ in 0..ITERATIONS * 50
+                {
+                    random ^= random << 13;
+                    random ^= random >> 7;
+                    random ^= random << 17;
+
+                    let a = &keeps[random as usize % KEEPS];
+                    let b = &keeps[(random >> 8) as usize % KEEPS];
+
+                    match (random >> 16) % 3
+                    {
+                        0 => a.swap_with(b),
+                        1 =>
+                        {
+                            let _ = a.exchange_with(a.mark(), b);
+                        }
+                        _ => assert!(a.read().value < KEEPS),
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for thread in threads
+    {
+        thread.join().unwrap();
+    }
+
+    // Every value is still kept exactly once, only their order changed
+    let mut values: Vec<_> = keeps.iter().map(|keep| keep.read().value).collect();
+    values.sort();
+    assert_eq!((0..KEEPS).collect::<Vec<_>>(), values);
+
+    drop(Arc::into_inner(keeps).unwrap());
+
+    assert_eq!(KEEPS, counters.created.load(Ordering::SeqCst));
+    assert_eq!(KEEPS, counters.dropped.load(Ordering::SeqCst));
+}
+
+
+#[test]
+fn two_overlapping_swaps_finish_while_readers_help()
+{
+    let keeps: Arc<[Keep<usize>; 3]> = Arc::new([Keep::new(0), Keep::new(1), Keep::new(2)]);
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|id| {
+            let keeps = keeps.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                barrier.wait();
+
+                // Both swaps share the middle keep, which every other thread keeps reading
+                for _ in 0..ITERATIONS * 10
+                {
+                    match id
+                    {
+                        0 => keeps[0].swap_with(&keeps[1]),
+                        1 => keeps[1].swap_with(&keeps[2]),
+                        _ => assert!(*keeps[1].read() < 3),
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for thread in threads
+    {
+        thread.join().unwrap();
+    }
+
+    let mut values: Vec<_> = keeps.iter().map(|keep| *keep.read()).collect();
+    values.sort();
+    assert_eq!(vec![0, 1, 2], values);
+}
+
+
+#[test]
+fn snapshots_see_transactions_at_once()
+{
+    const WRITERS: usize = 2;
+
+    let keeps = Arc::new((Keep::new(0usize), Keep::new(0usize)));
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|id| {
+            let keeps = keeps.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                let (keep_a, keep_b) = &*keeps;
+                barrier.wait();
+
+                for _ in 0..ITERATIONS
+                {
+                    if id < WRITERS
+                    {
+                        transaction(|tx| {
+                            let a = tx.read(keep_a);
+                            let b = tx.read(keep_b);
+
+                            tx.write(keep_a, *a + 1);
+                            tx.write(keep_b, *b + 1);
+                        });
+
+                        continue;
+                    }
+
+                    let (a, b) = snapshot((keep_a, keep_b));
+                    assert_eq!(*a, *b, "a snapshot saw half of a transaction");
+                }
+            })
+        })
+        .collect();
+
+    for thread in threads
+    {
+        thread.join().unwrap();
+    }
+
+    // Transactions that read outdated values were run again, so no increment got lost
+    assert_eq!(WRITERS * ITERATIONS, *keeps.0.read());
+    assert_eq!(WRITERS * ITERATIONS, *keeps.1.read());
+}
+
+
+#[test]
+fn snapshots_of_zero_sized_values_see_transactions_at_once()
+{
+    const WRITERS: usize = 2;
+
+    // Every box of a zero sized value has the same address, so only their versions tell them apart
+    let keeps = Arc::new((Keep::new(()), Keep::new(())));
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|id| {
+            let keeps = keeps.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                let (keep_a, keep_b) = &*keeps;
+                barrier.wait();
+
+                for _ in 0..ITERATIONS
+                {
+                    if id < WRITERS
+                    {
+                        transaction(|tx| {
+                            tx.write(keep_a, ());
+                            tx.write(keep_b, ());
+                        });
+
+                        continue;
+                    }
+
+                    let (a, b) = snapshot((keep_a, keep_b));
+                    assert_eq!(a.version(), b.version(), "a snapshot saw half of a transaction");
+                }
+            })
+        })
+        .collect();
+
+    for thread in threads
+    {
+        thread.join().unwrap();
+    }
+}
+
+
+#[test]
+fn overlapping_transactions_keep_their_sum()
+{
+    const KEEPS: usize = 3;
+    const WRITERS: usize = 4;
+
+    let keeps: Arc<Vec<_>> = Arc::new((0..KEEPS).map(|_| Keep::new(100usize)).collect());
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|id| {
+            let keeps = keeps.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                barrier.wait();
+
+                for i in 0..ITERATIONS
+                {
+                    // Writers move a unit between different pairs of keeps, which share a keep with each other pair
+                    if id < WRITERS
+                    {
+                        let from = &keeps[(id + i) % KEEPS];
+                        let to = &keeps[(id + i + 1) % KEEPS];
+
+                        transaction(|tx| {
+                            let (a, b) = (tx.read(from), tx.read(to));
+
+                            if *a > 0
+                            {
+                                tx.write(from, *a - 1);
+                                tx.write(to, *b + 1);
+                            }
+                        });
+
+                        continue;
+                    }
+
+                    let (a, b, c) = snapshot((&keeps[0], &keeps[1], &keeps[2]));
+                    assert_eq!(KEEPS * 100, *a + *b + *c, "a snapshot saw half of a transaction");
+                }
+            })
+        })
+        .collect();
+
+    for thread in threads
+    {
+        thread.join().unwrap();
+    }
+
+    assert_eq!(KEEPS * 100, keeps.iter().map(|keep| *keep.read()).sum::<usize>());
+}
+
+
+#[test]
+fn watchers_are_woken_by_writes()
+{
+    let keep = Keep::new(0usize);
+    let barrier = Arc::new(Barrier::new(THREADS + 1));
+
+    let watchers: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let mut watcher = keep.subscribe();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                barrier.wait();
+
+                let mut last = **watcher.seen();
+
+                while last < ITERATIONS
+                {
+                    let value = *watcher.changed();
+                    assert!(last < value, "a watcher saw an old value");
+                    last = value;
+                }
+            })
+        })
+        .collect();
+
+    barrier.wait();
+
+    for i in 1..=ITERATIONS
+    {
+        keep.write(i);
+    }
+
+    for watcher in watchers
+    {
+        watcher.join().unwrap();
+    }
+}
+
+
+#[test]
+fn changed_futures_are_woken_by_writes()
+{
+    let keep = Arc::new(Keep::new(0usize));
+    let done = Arc::new(AtomicUsize::new(0));
+
+    let watchers: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let keep = keep.clone();
+            let done = done.clone();
+
+            thread::spawn(move || {
+                let mut last = 0;
+
+                while last < ITERATIONS
+                {
+                    let value = *block_on(keep.changed());
+                    assert!(last <= value, "a future saw an old value");
+                    last = value;
+                }
+
+                done.fetch_add(1, Ordering::SeqCst);
+            })
+        })
+        .collect();
+
+    for i in 1..=ITERATIONS
+    {
+        keep.write(i);
+    }
+
+    // Futures created after the last write wait for another one
+    while done.load(Ordering::SeqCst) < THREADS
+    {
+        keep.write(ITERATIONS);
+        thread::yield_now();
+    }
+
+    for watcher in watchers
+    {
+        watcher.join().unwrap();
+    }
+}
+
+
+#[test]
+fn weak_keeps_across_threads()
+{
+    let counters = Arc::new(Counters::default());
+
+    for _ in 0..ITERATIONS / 10
+    {
+        let keep = Keep::new(Counted::new(39, &counters));
+        let barrier = Arc::new(Barrier::new(THREADS + 1));
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let weak = keep.downgrade();
+                let barrier = barrier.clone();
+
+                thread::spawn(move || {
+                    barrier.wait();
+
+                    if let Some(keep) = weak.upgrade()
+                    {
+                        assert_eq!(39, keep.read().value);
+                    }
+                })
+            })
+            .collect();
+
+        barrier.wait();
+        drop(keep);
+
+        for thread in threads
+        {
+            thread.join().unwrap();
+        }
+    }
+
+    assert_eq!(
+        counters.created.load(Ordering::SeqCst),
+        counters.dropped.load(Ordering::SeqCst)
+    );
+}
+
+
+#[test]
+fn try_unwrap_across_threads()
+{
+    let counters = Arc::new(Counters::default());
+
+    for _ in 0..ITERATIONS / 10
+    {
+        let keep = Keep::new(Counted::new(39, &counters));
+        let barrier = Arc::new(Barrier::new(THREADS));
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let keep = keep.clone();
+                let barrier = barrier.clone();
+
+                thread::spawn(move || {
+                    barrier.wait();
+
+                    let guard = keep.read();
+                    drop(keep);
+                    guard.try_unwrap().ok()
+                })
+            })
+            .collect();
+
+        keep.write(Counted::new(14, &counters));
+
+        let unwrapped: Vec<_> = threads
+            .into_iter()
+            .filter_map(|thread| thread.join().unwrap())
+            .collect();
+
+        // Only a guard of the replaced value can be unwrapped, by a single thread at most
+        assert!(unwrapped.len() <= 1);
+        assert!(unwrapped.iter().all(|counted| counted.value == 39));
+
+        assert_eq!(14, keep.try_unwrap().ok().unwrap().value);
+    }
+
+    assert_eq!(
+        counters.created.load(Ordering::SeqCst),
+        counters.dropped.load(Ordering::SeqCst)
+    );
+}
+
+
+#[test]
+fn upgrades_while_unwrapping()
+{
+    let keep = Keep::new(39);
+    let weak = keep.downgrade();
+    let unwrapping = Arc::new(AtomicBool::new(true));
+
+    // The guard makes every unwrap fail, so the keep is alive all the time
+    let guard = keep.read();
+
+    let upgrader = {
+        let unwrapping = unwrapping.clone();
+
+        thread::spawn(move || {
+            while unwrapping.load(Ordering::SeqCst)
+            {
+                assert!(weak.upgrade().is_some(), "a weak keep did not upgrade while its keep was alive");
+            }
+        })
+    };
+
+    let mut keep = keep;
+
+    for _ in 0..ITERATIONS * 10
+    {
+        keep = keep.try_unwrap().unwrap_err();
+    }
+
+    unwrapping.store(false, Ordering::SeqCst);
+    upgrader.join().unwrap();
+
+    drop(guard);
+    assert_eq!(Some(39), keep.try_unwrap().ok());
+}
+
+
+#[test]
+fn nodes_are_trimmed_after_concurrent_spikes()
+{
+    let keep = Arc::new(Keep::new(0usize));
+    drop(keep.read());
+    let idle = keep.node_count();
+
+    for round in 0..10
+    {
+        let barrier = Arc::new(Barrier::new(THREADS));
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let keep = keep.clone();
+                let barrier = barrier.clone();
+
+                thread::spawn(move || {
+                    let guards: Vec<_> = (0..ITERATIONS / 10).map(|_| keep.read()).collect();
+                    barrier.wait();
+
+                    assert!(guards.iter().all(|guard| **guard <= round));
+                })
+            })
+            .collect();
+
+        for thread in threads
+        {
+            thread.join().unwrap();
+        }
+
+        keep.write(round + 1);
+        assert_eq!(idle, keep.node_count());
+    }
+}
@@ -0,0 +1,184 @@
+//! Compares the copy-on-write buckets of `PlugMap` with the linked chains of nodes it used before.
+//!
+//! A bucket is filled by inserting one key after another and then searched for each of them.
+//! Tables grow beyond 3/4 entries per bucket, so most buckets hold only one or two nodes.
+//!
+//! Run with `cargo bench -p plugmap --bench buckets`.
+
+use keep::*;
+use std::{hint::black_box, time::Instant};
+
+
+const LENGTHS: [usize; 5] = [1, 2, 4, 8, 16];
+const ROUNDS: usize = 20_000;
+
+
+/// A key and its value, which are shared by the copies of a bucket, like `EntryNode`.
+#[derive(Clone)]
+struct Node
+{
+    key: Keep<usize>,
+    val: Keep<usize>,
+}
+
+
+/// A bucket that is replaced by a copy holding one more node on every insert, like `Entry::Head`.
+struct Slice(Keep<Box<[Node]>>);
+
+
+impl Slice
+{
+    fn new() -> Self
+    {
+        Self(Keep::new(Box::<[Node]>::default()))
+    }
+
+    fn insert(&self, key: usize)
+    {
+        let node = Node {
+            key: Keep::new(key),
+            val: Keep::new(key),
+        };
+
+        loop
+        {
+            let nodes = self.0.read();
+            let new: Box<[_]> = nodes.iter().chain([&node]).cloned().collect();
+
+            if self.0.exchange(&nodes, new).is_ok()
+            {
+                break;
+            }
+        }
+    }
+
+    fn get(&self, key: usize) -> Option<Guard<usize>>
+    {
+        self.0
+            .read()
+            .iter()
+            .find(|node| node.key.read_with(|own| *own == key))
+            .map(|node| node.val.read())
+    }
+}
+
+
+struct Link
+{
+    key: usize,
+    val: Keep<usize>,
+    next: Keep<Option<Keep<Link>>>,
+}
+
+
+/// A bucket whose nodes link to each other, new nodes are appended to the last one.
+struct Chain(Keep<Option<Keep<Link>>>);
+
+
+impl Chain
+{
+    fn new() -> Self
+    {
+        Self(Keep::new(None))
+    }
+
+    fn insert(&self, key: usize)
+    {
+        let link = Keep::new(Link {
+            key,
+            val: Keep::new(key),
+            next: Keep::new(None),
+        });
+
+        let mut next = self.0.clone();
+
+        loop
+        {
+            let current = next.read();
+
+            match &*current
+            {
+                Some(node) => next = node.read().next.clone(),
+                None =>
+                {
+                    if next.exchange(&current, Some(link.clone())).is_ok()
+                    {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    fn get(&self, key: usize) -> Option<Guard<usize>>
+    {
+        let mut next = self.0.read();
+
+        loop
+        {
+            let node = (*next).as_ref()?.read();
+
+            if node.key == key
+            {
+                break Some(node.val.read());
+            }
+
+            next = node.next.read();
+        }
+    }
+}
+
+
+/// Returns the nanoseconds per insert and per lookup of filling `length` keys into a new bucket from `new`.
+fn measure<B>(
+    length: usize,
+    new: impl Fn() -> B,
+    insert: impl Fn(&B, usize),
+    get: impl Fn(&B, usize) -> bool,
+) -> (f64, f64)
+{
+    let mut inserts = 0;
+    let mut gets = 0;
+
+    for _ in 0..ROUNDS
+    {
+        let bucket = new();
+
+        let start = Instant::now();
+
+        for key in 0..length
+        {
+            insert(&bucket, key);
+        }
+
+        inserts += start.elapsed().as_nanos();
+        let start = Instant::now();
+
+        for key in 0..length
+        {
+            assert!(get(black_box(&bucket), key));
+        }
+
+        gets += start.elapsed().as_nanos();
+    }
+
+    let operations = (ROUNDS * length) as f64;
+    (inserts as f64 / operations, gets as f64 / operations)
+}
+
+
+fn main()
+{
+    println!("nanoseconds per operation on a bucket of n nodes");
+
+    for length in LENGTHS
+    {
+        let (slice_insert, slice_get) = measure(length, Slice::new, Slice::insert, |b, k| b.get(k).is_some());
+        let (chain_insert, chain_get) = measure(length, Chain::new, Chain::insert, |b, k| b.get(k).is_some());
+
+        println!(
+            "  n = {length:>2}: insert {slice_insert:>6.0} slice, {chain_insert:>6.0} chain; \
+             get {slice_get:>5.0} slice, {chain_get:>5.0} chain"
+        );
+    }
+}
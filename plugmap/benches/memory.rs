@@ -0,0 +1,127 @@
+//! Measures how many allocations and bytes a map holds per entry.
+//!
+//! Run with `cargo bench -p plugmap --bench memory`. Bytes are the sizes requested from the allocator,
+//! without its own overhead. On x86_64 this currently prints:
+//!
+//! ```text
+//! keep of a usize: 2 allocations, 200 bytes
+//! keep of a usize after a read: 2 allocations, 200 bytes
+//! plugmap of usize to usize
+//!       1000 entries:   8.9 allocations,  894.9 bytes per entry
+//!      10000 entries:   8.0 allocations,  798.5 bytes per entry
+//!     100000 entries:  12.6 allocations, 1312.8 bytes per entry
+//!    1000000 entries:   9.0 allocations,  901.8 bytes per entry
+//! ```
+//!
+//! A keep takes two allocations: its tracked atomic, which holds the domain with its first guard nodes,
+//! and the record of its value, which holds sized values inline. Arcs, reclaimers and watchers
+//! allocate a side block of the domain, which plain keeps never need.
+//! Every entry of a map has a keep for its key and one for its value, and shares the keep of its bucket
+//! with the other entries of the bucket, whose nodes take another allocation. The amount of buckets per entry
+//! depends on how far the table has filled up since it last grew, which is why 100000 entries take the most.
+
+use keep::*;
+use plugmap::PlugMap;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+
+const ENTRIES: [usize; 4] = [1_000, 10_000, 100_000, 1_000_000];
+
+
+/// Counts the allocations and bytes that are currently alive.
+struct Counting;
+
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+
+
+unsafe impl GlobalAlloc for Counting
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8
+    {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout)
+    {
+        ALLOCATIONS.fetch_sub(1, Ordering::Relaxed);
+        BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+
+fn live() -> (usize, usize)
+{
+    (ALLOCATIONS.load(Ordering::Relaxed), BYTES.load(Ordering::Relaxed))
+}
+
+
+/// Frees every dropped keep that is still waiting for its retirement to be processed
+/// and sets up the protection slot of this thread, so that neither is counted.
+fn flush()
+{
+    for _ in 0..64
+    {
+        drop(Keep::new(()).read());
+    }
+}
+
+
+/// Returns the allocations and bytes held by `f`'s result, divided by `per`.
+fn measure<R>(per: usize, f: impl FnOnce() -> R) -> (f64, f64)
+{
+    flush();
+    let (allocations, bytes) = live();
+    let result = f();
+    flush();
+    let (allocations_after, bytes_after) = live();
+    drop(result);
+
+    (
+        (allocations_after - allocations) as f64 / per as f64,
+        (bytes_after - bytes) as f64 / per as f64,
+    )
+}
+
+
+fn main()
+{
+    let (allocations, bytes) = measure(1, || Keep::new(0usize));
+    println!("keep of a usize: {allocations:.0} allocations, {bytes:.0} bytes");
+
+    let (allocations, bytes) = measure(1, || {
+        let keep = Keep::new(0usize);
+        drop(keep.read());
+        keep
+    });
+    println!("keep of a usize after a read: {allocations:.0} allocations, {bytes:.0} bytes");
+
+    println!("plugmap of usize to usize");
+
+    for entries in ENTRIES
+    {
+        let (allocations, bytes) = measure(entries, || {
+            let map = PlugMap::new();
+
+            for i in 0..entries
+            {
+                map.insert(i, i);
+            }
+
+            map
+        });
+
+        println!("  {entries:>8} entries: {allocations:>5.1} allocations, {bytes:>6.1} bytes per entry");
+    }
+}
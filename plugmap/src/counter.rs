@@ -0,0 +1,105 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        OnceLock,
+        atomic::{AtomicIsize, AtomicUsize, Ordering},
+    },
+    thread,
+};
+
+
+/// A counter split into stripes, so that threads counting at the same time don't contend on one cache line.
+///
+/// Every thread counts on its own stripe, reading the whole count sums all stripes.
+pub struct StripedCounter
+{
+    stripes: Box<[Stripe]>,
+}
+
+
+#[repr(align(128))]
+#[derive(Default)]
+struct Stripe(AtomicIsize);
+
+
+impl StripedCounter
+{
+    /// The maximum amount of stripes a counter is split into.
+    const MAX_STRIPES: usize = 64;
+
+    pub fn new() -> Self
+    {
+        Self {
+            stripes: (0..Self::stripe_count()).map(|_| Stripe::default()).collect(),
+        }
+    }
+
+    /// Adds `delta` to the stripe of the current thread.
+    #[inline]
+    pub fn add(&self, delta: isize)
+    {
+        self.stripe().0.fetch_add(delta, Ordering::SeqCst);
+    }
+
+    /// Returns the value of the current thread's stripe.
+    #[inline]
+    pub fn local(&self) -> isize
+    {
+        self.stripe().0.load(Ordering::SeqCst)
+    }
+
+    /// Sums all stripes of this counter.
+    pub fn sum(&self) -> isize
+    {
+        self.stripes
+            .iter()
+            .map(|stripe| stripe.0.load(Ordering::SeqCst))
+            .sum()
+    }
+
+    #[inline]
+    pub fn stripes(&self) -> usize
+    {
+        self.stripes.len()
+    }
+
+    #[inline]
+    fn stripe(&self) -> &Stripe
+    {
+        &self.stripes[Self::thread_index() & (self.stripes.len() - 1)]
+    }
+
+    /// Returns an index, unique to the current thread, that is used to pick its stripe.
+    fn thread_index() -> usize
+    {
+        static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+        thread_local! {
+            static INDEX: usize = NEXT_INDEX.fetch_add(1, Ordering::Relaxed);
+        }
+
+        INDEX.with(|index| *index)
+    }
+
+    /// One stripe per available core, so that every core is able to count on its own.
+    fn stripe_count() -> usize
+    {
+        static STRIPE_COUNT: OnceLock<usize> = OnceLock::new();
+
+        *STRIPE_COUNT.get_or_init(|| {
+            thread::available_parallelism()
+                .map_or(1, NonZeroUsize::get)
+                .next_power_of_two()
+                .min(Self::MAX_STRIPES)
+        })
+    }
+}
+
+
+impl Default for StripedCounter
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
@@ -1,10 +1,22 @@
 use keep::*;
+use std::borrow::Borrow;
 
 
+/// The contents of a single bucket of a table.
+///
+/// Entries are never modified in place, every change to a bucket exchanges its entry for a new one.
+/// This includes storing a new value for a key, so that a value can only change while its node is in the entry.
+/// Copying the few nodes of a bucket is cheaper than allocating the links of a chain, see `benches/buckets.rs`.
 pub enum Entry<Key, Val>
 {
     Empty,
-    Head(Keep<EntryNode<Key, Val>>),
+    Head(Box<[EntryNode<Key, Val>]>),
+
+    /// The nodes of this entry are being moved into the next table and can no longer change.
+    Frozen(Box<[EntryNode<Key, Val>]>),
+
+    /// The nodes of this entry have been moved into the next table.
+    Moved,
 }
 
 
@@ -12,23 +24,49 @@ impl<Key, Val> Entry<Key, Val>
 where
     Key: Eq,
 {
-    pub fn search(&self, key: &Key) -> Option<Guard<Val>>
+    /// Returns the nodes of this entry, or `None` if it has been moved.
+    pub fn nodes(&self) -> Option<&[EntryNode<Key, Val>]>
     {
         match self
         {
-            Entry::Empty => None,
-            Entry::Head(keep) => keep.read().search(key),
+            Entry::Empty => Some(&[]),
+            Entry::Head(nodes) | Entry::Frozen(nodes) => Some(nodes),
+            Entry::Moved => None,
         }
     }
+
+    pub fn search<Q>(&self, key: &Q) -> Option<Guard<Val>>
+    where
+        Key: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.nodes()?
+            .iter()
+            .find(|node| node.holds_key(key))
+            .and_then(EntryNode::value)
+    }
+
+    /// Returns the position of the node holding `key` within this entry.
+    pub fn position<Q>(&self, key: &Q) -> Option<usize>
+    where
+        Key: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.nodes()?.iter().position(|node| node.holds_key(key))
+    }
 }
 
 
+/// A key and its value within an entry.
+///
+/// Neither of them is modified in place. A new value is stored in a new node, that shares its key with the old one.
 pub struct EntryNode<Key, Val>
 {
-    val: Keep<Val>,
-    key: Key,
+    key: Keep<Key>,
+
+    /// The value of the key, or `None` while a thread that claimed the vacant key creates its value.
+    val: Option<Keep<Val>>,
     hash: u64,
-    next: Keep<Option<Keep<EntryNode<Key, Val>>>>,
 }
 
 
@@ -36,16 +74,17 @@ impl<Key, Val> EntryNode<Key, Val>
 where
     Key: Eq,
 {
+    /// Returns the value of this node, or `None` if its key is claimed but has no value yet.
     #[inline]
-    pub fn value(&self) -> Guard<Val>
+    pub fn value(&self) -> Option<Guard<Val>>
     {
-        self.val.read()
+        self.val.as_ref().map(Keep::read)
     }
 
     #[inline]
-    pub fn next(&self) -> &Keep<Option<Keep<EntryNode<Key, Val>>>>
+    pub fn has_value(&self) -> bool
     {
-        &self.next
+        self.val.is_some()
     }
 
     #[inline]
@@ -55,64 +94,62 @@ where
     }
 
     #[inline]
-    pub fn key(&self) -> &Key
+    pub fn key(&self) -> Guard<Key>
+    {
+        self.key.read()
+    }
+
+    /// Returns whether this node holds `key`, without guarding its key.
+    #[inline]
+    pub fn holds_key<Q>(&self, key: &Q) -> bool
+    where
+        Key: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.key.read_with(|own| own.borrow() == key)
+    }
+
+    /// Returns whether this node is the claim that was made with `key`, and has no value yet.
+    pub fn is_claim_of(&self, key: &Keep<Key>) -> bool
     {
-        &self.key
+        self.val.is_none() && Guard::same_value(&self.key.read(), &key.read())
     }
 
-    pub fn new(key: Key, val: Val, hash: u64) -> Self
+    pub fn new(key: Keep<Key>, val: Keep<Val>, hash: u64) -> Self
     {
         Self {
-            val: Keep::new(val),
             key,
+            val: Some(val),
             hash,
-            next: Keep::new(None),
         }
     }
 
-    pub fn update(&self, node: &Keep<EntryNode<Key, Val>>) -> Option<Guard<Val>>
+    /// Returns a node that claims the vacant `key`, so that other threads wait until a value is stored for it.
+    pub fn claim(key: Keep<Key>, hash: u64) -> Self
     {
-        if self.key == node.read().key
-        {
-            self.val.swap_with(&node.read().val);
-            return Some(node.read().value());
-        }
-
-        let next = &self.next;
-        let mut next_guard = next.read();
-
-        loop
-        {
-            match &*next_guard
-            {
-                Some(next) => return next.read().update(node),
-
-                None =>
-                {
-                    match next.exchange(&next_guard, Some(node.clone()))
-                    {
-                        Ok(_old) => return None,
-                        Err(actual) =>
-                        {
-                            next_guard = actual;
-                        }
-                    }
-                }
-            }
-        }
+        Self { key, val: None, hash }
     }
 
-    pub fn search(&self, key: &Key) -> Option<Guard<Val>>
+    /// Returns a node holding `val` for the key of this node.
+    pub fn with_value(&self, val: Keep<Val>) -> Self
     {
-        if &self.key == key
-        {
-            return Some(self.value());
+        Self {
+            key: self.key.clone(),
+            val: Some(val),
+            hash: self.hash,
         }
+    }
+}
 
-        match &*self.next.read()
-        {
-            Some(next) => next.read().search(key),
-            None => None,
+
+impl<Key, Val> Clone for EntryNode<Key, Val>
+{
+    fn clone(&self) -> Self
+    {
+        Self {
+            key: self.key.clone(),
+            val: self.val.clone(),
+            hash: self.hash,
         }
     }
 }
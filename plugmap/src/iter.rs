@@ -0,0 +1,199 @@
+use crate::{
+    entry::{Entry, EntryNode},
+    table::Table,
+};
+use keep::*;
+use std::{marker::PhantomData, ops::Deref};
+
+
+/// Walks every node of a table, following moved entries into the tables they have been moved into.
+///
+/// Every entry is read exactly once, when the walk reaches it.
+/// Nodes are never yielded twice, because moved entries are only visited in the table they were moved into.
+pub(crate) struct Nodes<Key, Val>
+{
+    table: Keep<Table<Key, Val>>,
+    index: usize,
+
+    /// Entries of next tables, that still have to be visited.
+    moved: Vec<(Keep<Table<Key, Val>>, usize)>,
+
+    entry: Option<Guard<Entry<Key, Val>>>,
+    position: usize,
+}
+
+
+impl<Key, Val> Nodes<Key, Val>
+where
+    Key: Eq,
+{
+    pub fn new(table: Keep<Table<Key, Val>>) -> Self
+    {
+        Self {
+            table,
+            index: 0,
+            moved: Vec::new(),
+            entry: None,
+            position: 0,
+        }
+    }
+
+    /// Reads the next entry to visit, or returns `false` if there is none left.
+    fn next_entry(&mut self) -> bool
+    {
+        let (table, index) = match self.moved.pop()
+        {
+            Some((table, index)) => (table.read(), index),
+            None if self.index < self.table.read().capacity() =>
+            {
+                self.index += 1;
+                (self.table.read(), self.index - 1)
+            }
+            None => return false,
+        };
+
+        let entry = table.entry_at(index).read();
+
+        match &*entry
+        {
+            // A moved entry has been split into two entries of the next table
+            Entry::Moved =>
+            {
+                let next = table
+                    .next_table()
+                    .expect("entries are only moved after the next table has been set");
+
+                self.moved.push((next.clone(), index + table.capacity()));
+                self.moved.push((next, index));
+            }
+
+            _ =>
+            {
+                self.entry = Some(entry);
+                self.position = 0;
+            }
+        }
+
+        true
+    }
+}
+
+
+impl<Key, Val> Iterator for Nodes<Key, Val>
+where
+    Key: Eq,
+{
+    type Item = EntryNode<Key, Val>;
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        loop
+        {
+            if let Some(entry) = &self.entry
+            {
+                if let Some(node) = entry.nodes().and_then(|nodes| nodes.get(self.position))
+                {
+                    self.position += 1;
+                    return Some(node.clone());
+                }
+
+                self.entry = None;
+            }
+
+            if !self.next_entry()
+            {
+                return None;
+            }
+        }
+    }
+}
+
+
+/// Guards the key of an entry of a `PlugMap`.
+pub struct KeyGuard<Key, Val>(Guard<Key>, PhantomData<Val>);
+
+
+impl<Key: Eq, Val> KeyGuard<Key, Val>
+{
+    fn new(node: &EntryNode<Key, Val>) -> Self
+    {
+        Self(node.key(), PhantomData)
+    }
+}
+
+
+impl<Key: Eq, Val> Deref for KeyGuard<Key, Val>
+{
+    type Target = Key;
+
+    fn deref(&self) -> &Key
+    {
+        &self.0
+    }
+}
+
+
+impl<Key: Eq, Val> AsRef<Key> for KeyGuard<Key, Val>
+{
+    fn as_ref(&self) -> &Key
+    {
+        &self.0
+    }
+}
+
+
+impl<Key: Eq + std::fmt::Debug, Val> std::fmt::Debug for KeyGuard<Key, Val>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        f.debug_tuple("KeyGuard").field(&self.0).finish()
+    }
+}
+
+
+/// An iterator over the entries of a `PlugMap`, created by `PlugMap::iter()`.
+pub struct Iter<Key, Val>(pub(crate) Nodes<Key, Val>);
+
+
+impl<Key: Eq, Val> Iterator for Iter<Key, Val>
+{
+    type Item = (KeyGuard<Key, Val>, Guard<Val>);
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        // Claimed keys are skipped, as they are vacant until their value is stored
+        let (node, value) = self.0.find_map(|node| node.value().map(|value| (node, value)))?;
+
+        Some((KeyGuard::new(&node), value))
+    }
+}
+
+
+/// An iterator over the keys of a `PlugMap`, created by `PlugMap::keys()`.
+pub struct Keys<Key, Val>(pub(crate) Nodes<Key, Val>);
+
+
+impl<Key: Eq, Val> Iterator for Keys<Key, Val>
+{
+    type Item = KeyGuard<Key, Val>;
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        self.0.find(EntryNode::has_value).map(|node| KeyGuard::new(&node))
+    }
+}
+
+
+/// An iterator over the values of a `PlugMap`, created by `PlugMap::values()`.
+pub struct Values<Key, Val>(pub(crate) Nodes<Key, Val>);
+
+
+impl<Key: Eq, Val> Iterator for Values<Key, Val>
+{
+    type Item = Guard<Val>;
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        self.0.find_map(|node| node.value())
+    }
+}
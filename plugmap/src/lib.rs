@@ -1,18 +1,31 @@
 #![allow(unused)]
 
 
+mod counter;
 mod entry;
+mod iter;
 mod map;
+mod map_entry;
 mod table;
 
 
+pub use iter::{Iter, KeyGuard, Keys, Values};
 pub use map::PlugMap;
+pub use map_entry::Entry;
 
 
 #[cfg(test)]
 mod tests
 {
     use super::*;
+    use std::{
+        hash::RandomState,
+        sync::{
+            Arc,
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+        },
+        thread,
+    };
 
     #[test]
     fn look_and_feel()
@@ -73,4 +86,683 @@ mod tests
         assert_eq!(None, map.get(&39));
         assert_eq!(Some("31"), map.get(&31).as_ref().map(|g| g.as_str()));
     }
+
+
+    #[test]
+    fn shared_between_threads()
+    {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<PlugMap<String, String>>();
+
+        let map = Arc::new(PlugMap::new());
+
+        let writers: Vec<_> = (0..4)
+            .map(|id| {
+                let map = map.clone();
+
+                thread::spawn(move || {
+                    for i in (id * 1000)..(id * 1000 + 1000)
+                    {
+                        map.insert(i, i.to_string());
+                        assert_eq!(Some(i.to_string()), map.get(&i).map(|g| g.to_string()));
+                    }
+                })
+            })
+            .collect();
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let map = map.clone();
+
+                thread::spawn(move || {
+                    for i in 0..4000
+                    {
+                        if let Some(value) = map.get(&i)
+                        {
+                            assert_eq!(i.to_string(), *value);
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for thread in writers.into_iter().chain(readers)
+        {
+            thread.join().unwrap();
+        }
+
+        for i in 0..4000
+        {
+            assert_eq!(Some(i.to_string()), map.get(&i).map(|g| g.to_string()));
+        }
+    }
+
+
+    #[test]
+    fn grows_when_overloaded()
+    {
+        let map = PlugMap::new();
+        let initial_capacity = map.capacity();
+
+        for i in 0..10_000
+        {
+            map.insert(i, i);
+        }
+
+        assert!(initial_capacity < map.capacity(), "the table did not grow");
+
+        for i in 0..10_000
+        {
+            assert_eq!(Some(i), map.get(&i).map(|g| *g), "lost {i} while resizing");
+        }
+    }
+
+
+    #[test]
+    fn resizes_while_shared()
+    {
+        let map = Arc::new(PlugMap::new());
+
+        let threads: Vec<_> = (0..8)
+            .map(|id| {
+                let map = map.clone();
+
+                thread::spawn(move || {
+                    for i in (id * 2000)..(id * 2000 + 2000)
+                    {
+                        assert_eq!(None, map.insert(i, i).map(|g| *g));
+
+                        // Remove every odd key again, while the table is being resized by others
+                        if i % 2 == 1
+                        {
+                            assert_eq!(Some(i), map.remove(&i).map(|g| *g));
+                        }
+
+                        assert_eq!(i % 2 == 0, map.get(&i).is_some());
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads
+        {
+            thread.join().unwrap();
+        }
+
+        for i in 0..16_000
+        {
+            assert_eq!(i % 2 == 0, map.get(&i).is_some(), "wrong presence of {i}");
+        }
+    }
+
+
+    #[test]
+    fn reads_finish_resizes()
+    {
+        let map = PlugMap::new_with_hasher(10, RandomState::new());
+
+        // The resize starts beyond 3/4 of the capacity, after which every write only moves a few entries
+        for i in 0..800
+        {
+            map.insert(i, i);
+        }
+
+        assert_eq!(1024, map.capacity(), "the resize finished before the reads");
+
+        for i in 0..800
+        {
+            assert_eq!(Some(i), map.get(&i).map(|g| *g), "lost {i} while resizing");
+        }
+
+        assert_eq!(2048, map.capacity(), "reads did not finish the resize");
+    }
+
+
+    #[test]
+    fn len()
+    {
+        let map = PlugMap::new();
+        assert!(map.is_empty());
+
+        map.insert(39, "Briar");
+        map.insert(39, "Miku");
+        map.insert(31, "Other");
+        assert_eq!(2, map.len());
+
+        map.remove(&39);
+        map.remove(&39);
+        assert_eq!(1, map.len());
+
+        map.remove(&31);
+        assert!(map.is_empty());
+    }
+
+
+    #[test]
+    fn len_after_concurrent_writes()
+    {
+        let map = Arc::new(PlugMap::new());
+
+        let threads: Vec<_> = (0..8)
+            .map(|id| {
+                let map = map.clone();
+
+                thread::spawn(move || {
+                    for i in (id * 1000)..(id * 1000 + 1000)
+                    {
+                        map.insert(i, i);
+                        map.insert(i, i + 1);
+                    }
+
+                    for i in (id * 1000)..(id * 1000 + 500)
+                    {
+                        map.remove(&i);
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads
+        {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(4000, map.len());
+    }
+
+
+    #[test]
+    fn len_while_resizing()
+    {
+        for _ in 0..10
+        {
+            let map = Arc::new(PlugMap::new_with_hasher(10, RandomState::new()));
+
+            for i in 0..800
+            {
+                map.insert(i, i);
+            }
+
+            // Every read moves a few entries into the next table, while the length is checked in between
+            let reader = {
+                let map = map.clone();
+
+                thread::spawn(move || {
+                    while map.capacity() == 1024
+                    {
+                        for i in 0..800
+                        {
+                            map.get(&i);
+                        }
+                    }
+                })
+            };
+
+            while map.capacity() == 1024
+            {
+                assert_eq!(800, map.len(), "entries were counted in both tables");
+            }
+
+            reader.join().unwrap();
+        }
+    }
+
+
+    #[test]
+    fn iter()
+    {
+        let map = PlugMap::new();
+
+        for i in 0..100
+        {
+            map.insert(i, i.to_string());
+        }
+
+        let mut entries: Vec<_> = map.iter().map(|(k, v)| (*k, v.to_string())).collect();
+        entries.sort();
+        assert_eq!((0..100).map(|i| (i, i.to_string())).collect::<Vec<_>>(), entries);
+
+        let mut keys: Vec<_> = map.keys().map(|k| *k).collect();
+        keys.sort();
+        assert_eq!((0..100).collect::<Vec<_>>(), keys);
+
+        assert_eq!(4950, map.values().map(|v| v.parse::<usize>().unwrap()).sum::<usize>());
+        assert_eq!(100, (&map).into_iter().count());
+    }
+
+
+    #[test]
+    fn iter_while_resizing()
+    {
+        let map = Arc::new(PlugMap::new());
+
+        for i in 0..1000
+        {
+            map.insert(i, i);
+        }
+
+        let writer = {
+            let map = map.clone();
+            thread::spawn(move || {
+                for i in 1000..20_000
+                {
+                    map.insert(i, i);
+                }
+            })
+        };
+
+        // Keys present for the whole iteration are yielded exactly once
+        for _ in 0..10
+        {
+            let mut keys: Vec<_> = map.keys().map(|k| *k).filter(|k| *k < 1000).collect();
+            keys.sort();
+            assert_eq!((0..1000).collect::<Vec<_>>(), keys);
+        }
+
+        writer.join().unwrap();
+        assert_eq!(20_000, map.iter().count());
+    }
+
+
+    #[test]
+    fn entry()
+    {
+        let map = PlugMap::new();
+
+        assert_eq!("Briar", *map.entry(39).or_insert("Briar"));
+        assert_eq!("Briar", *map.entry(39).or_insert("Miku"));
+        assert_eq!("Briar", *map.entry(39).or_insert_with(|| unreachable!()));
+        assert_eq!("Miku", *map.entry(39).and_modify(|_| "Miku").or_insert("Other"));
+        assert_eq!("", *map.entry(31).and_modify(|_| unreachable!()).or_default());
+
+        let counts = PlugMap::new();
+        counts.entry("a").and_modify(|v| v + 1).and_modify(|v| v * 10).or_insert(1);
+        counts.entry("a").and_modify(|v| v + 1).and_modify(|v| v * 10).or_insert(1);
+        assert_eq!(Some(20), counts.get(&"a").map(|g| *g));
+    }
+
+
+    #[test]
+    fn entry_defaults_run_once_per_vacant_key()
+    {
+        let map = Arc::new(PlugMap::<usize, usize>::new());
+        let defaults: Arc<Vec<_>> = Arc::new((0..100).map(|_| AtomicUsize::new(0)).collect());
+
+        let threads: Vec<_> = (0..8)
+            .map(|thread| {
+                let map = map.clone();
+                let defaults = defaults.clone();
+
+                thread::spawn(move || {
+                    (0..100)
+                        .map(|i| {
+                            let value = map.entry(i).or_insert_with(|| {
+                                defaults[i].fetch_add(1, Ordering::SeqCst);
+
+                                // Give the other threads time to find the key vacant
+                                thread::yield_now();
+                                thread
+                            });
+
+                            *value
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        let values: Vec<_> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+
+        for i in 0..100
+        {
+            assert_eq!(1, defaults[i].load(Ordering::SeqCst));
+            assert!(values.iter().all(|values| values[i] == *map.get(&i).unwrap()));
+        }
+
+        assert_eq!(100, map.len());
+    }
+
+
+    #[test]
+    fn entry_claims_are_released_when_default_panics()
+    {
+        let map = PlugMap::<usize, usize>::new();
+
+        let result = std::panic::catch_unwind(|| {
+            map.entry(1).or_insert_with(|| panic!("no default"));
+        });
+
+        assert!(result.is_err());
+        assert_eq!(None, map.get(&1).as_deref());
+        assert_eq!(0, map.iter().count());
+        assert_eq!(2, *map.entry(1).or_insert(2));
+        assert_eq!(1, map.len());
+    }
+
+
+    #[test]
+    fn entry_counts_across_threads()
+    {
+        let map = Arc::new(PlugMap::new());
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let map = map.clone();
+
+                thread::spawn(move || {
+                    for i in 0..4000
+                    {
+                        map.entry(i % 100).and_modify(|count| count + 1).or_insert(1);
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads
+        {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(100, map.len());
+        assert!(map.values().all(|count| *count == 8 * 40));
+    }
+
+
+    #[test]
+    fn borrowed_lookups()
+    {
+        let map = PlugMap::<String, usize>::new();
+        map.insert(String::from("Briar"), 39);
+
+        assert!(map.contains_key("Briar"));
+        assert!(!map.contains_key("Miku"));
+        assert_eq!(Some(39), map.get("Briar").map(|g| *g));
+        assert_eq!(Some(39), map.remove("Briar").map(|g| *g));
+        assert!(!map.contains_key("Briar"));
+    }
+
+
+    #[test]
+    fn update()
+    {
+        let map = PlugMap::new();
+        map.insert(39, 1);
+
+        assert_eq!(Some(1), map.update(&39, |val| val + 1).map(|g| *g));
+        assert_eq!(Some(2), map.get(&39).map(|g| *g));
+        assert!(map.update(&40, |val| val + 1).is_none());
+        assert!(!map.contains_key(&40));
+    }
+
+
+    #[test]
+    fn update_races_remove()
+    {
+        let map = Arc::new(PlugMap::new());
+
+        for i in 0..1000
+        {
+            map.insert(i, 0);
+        }
+
+        let updaters: Vec<_> = (0..4)
+            .map(|_| {
+                let map = map.clone();
+
+                thread::spawn(move || {
+                    // Counts the updates reported as successful per key, until every key has been removed
+                    let mut updates = vec![0; 1000];
+                    let mut present = true;
+
+                    while present
+                    {
+                        present = false;
+
+                        for (i, count) in updates.iter_mut().enumerate()
+                        {
+                            if map.update(&i, |val| val + 1).is_some()
+                            {
+                                *count += 1;
+                                present = true;
+                            }
+                        }
+                    }
+
+                    updates
+                })
+            })
+            .collect();
+
+        let removed: Vec<_> = (0..1000)
+            .map(|i| {
+                thread::yield_now();
+                *map.remove(&i).unwrap()
+            })
+            .collect();
+
+        let mut updates = vec![0; 1000];
+
+        for updater in updaters
+        {
+            for (i, count) in updater.join().unwrap().into_iter().enumerate()
+            {
+                updates[i] += count;
+            }
+        }
+
+        assert_eq!(removed, updates, "an update of a removed key was reported as successful");
+    }
+
+
+    #[test]
+    fn compute()
+    {
+        let map = PlugMap::new();
+
+        assert!(map.compute(39, |val| val.map(|v| v + 1)).is_none());
+        assert!(!map.contains_key(&39));
+
+        assert!(map.compute(39, |val| Some(val.map_or(1, |v| v + 1))).is_none());
+        assert_eq!(Some(1), map.get(&39).map(|g| *g));
+
+        assert_eq!(Some(1), map.compute(39, |val| Some(val.map_or(1, |v| v + 1))).map(|g| *g));
+        assert_eq!(Some(2), map.get(&39).map(|g| *g));
+
+        assert_eq!(Some(2), map.compute(39, |_| None).map(|g| *g));
+        assert!(!map.contains_key(&39));
+        assert_eq!(0, map.len());
+    }
+
+
+    #[test]
+    fn compute_across_threads()
+    {
+        let map = Arc::new(PlugMap::new());
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let map = map.clone();
+
+                thread::spawn(move || {
+                    for i in 0..4000
+                    {
+                        map.compute(i % 100, |count| Some(count.map_or(1, |c| c + 1)));
+                        map.update(&(i % 100), |count| count + 1);
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads
+        {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(100, map.len());
+        assert!(map.values().all(|count| *count == 2 * 8 * 40));
+    }
+
+
+    #[test]
+    fn try_insert()
+    {
+        let map = PlugMap::new();
+
+        assert_eq!(Ok(1), map.try_insert(39, 1).map(|g| *g).map_err(|g| *g));
+        assert_eq!(Err(1), map.try_insert(39, 2).map(|g| *g).map_err(|g| *g));
+        assert_eq!(Some(1), map.get(&39).map(|g| *g));
+    }
+
+
+    #[test]
+    fn try_insert_across_threads()
+    {
+        let map = Arc::new(PlugMap::new());
+
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let map = map.clone();
+
+                thread::spawn(move || (0..1000).filter(|&i| map.try_insert(i, t).is_ok()).count())
+            })
+            .collect();
+
+        let inserted: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+
+        assert_eq!(1000, inserted);
+        assert_eq!(1000, map.len());
+    }
+
+
+    #[test]
+    fn remove_if()
+    {
+        let map = PlugMap::new();
+        map.insert(39, 1);
+
+        assert!(map.remove_if(&39, |val| *val == 2).is_none());
+        assert!(map.contains_key(&39));
+        assert!(map.remove_if(&40, |_| true).is_none());
+
+        assert_eq!(Some(1), map.remove_if(&39, |val| *val == 1).map(|g| *g));
+        assert!(!map.contains_key(&39));
+        assert_eq!(0, map.len());
+    }
+
+
+    #[test]
+    fn remove_if_races_insert()
+    {
+        let map = Arc::new(PlugMap::new());
+        let inserting = Arc::new(AtomicBool::new(true));
+
+        let inserter = {
+            let map = map.clone();
+            let inserting = inserting.clone();
+
+            thread::spawn(move || {
+                let replaced: Vec<usize> = (0..20_000)
+                    .filter_map(|val| map.insert(val % 10, val).map(|g| *g))
+                    .collect();
+
+                inserting.store(false, Ordering::SeqCst);
+                replaced
+            })
+        };
+
+        // Only removes multiples of three, which the inserter keeps replacing
+        let mut removed = Vec::new();
+
+        while inserting.load(Ordering::SeqCst)
+        {
+            for key in 0..10
+            {
+                // Yielding in the condition lets the inserter replace the value before the removal
+                if let Some(val) = map.remove_if(&key, |val| {
+                    thread::yield_now();
+                    val % 3 == 0
+                })
+                {
+                    removed.push(*val);
+                }
+            }
+        }
+
+        assert!(removed.iter().all(|val| val % 3 == 0), "removed a value that did not match");
+
+        // Every inserted value has either been replaced, removed or is still present
+        let mut values = inserter.join().unwrap();
+        values.extend(&removed);
+        values.extend((0..10).filter_map(|key| map.get(&key).map(|g| *g)));
+        values.sort();
+
+        assert_eq!((0..20_000).collect::<Vec<_>>(), values, "a value was lost or removed twice");
+    }
+
+
+    #[test]
+    fn compare_exchange()
+    {
+        let map = PlugMap::new();
+        map.insert(39, 1);
+
+        let current = map.get(&39).unwrap();
+        assert_eq!(Ok(1), map.compare_exchange(&39, &current, 2).map(|g| *g).map_err(|_| ()));
+
+        // The guard does not guard the current value anymore, even though an equal value is inserted
+        map.insert(39, 1);
+        let actual = map.compare_exchange(&39, &current, 3).unwrap_err();
+        assert_eq!(Some(1), actual.map(|g| *g));
+
+        assert!(map.compare_exchange(&40, &current, 4).unwrap_err().is_none());
+        assert!(!map.contains_key(&40));
+    }
+
+
+    #[test]
+    fn compare_exchange_of_zero_sized_values()
+    {
+        let map = PlugMap::new();
+        map.insert(39, ());
+
+        // Every zero sized value has the same address, so only their identity tells them apart
+        let stale = map.get(&39).unwrap();
+        map.insert(39, ());
+
+        let actual = map.compare_exchange(&39, &stale, ()).unwrap_err().unwrap();
+        assert!(map.compare_exchange(&39, &actual, ()).is_ok());
+    }
+
+
+    #[test]
+    fn compare_exchange_across_threads()
+    {
+        let map = Arc::new(PlugMap::new());
+        map.insert(39, 0);
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let map = map.clone();
+
+                thread::spawn(move || {
+                    for _ in 0..1000
+                    {
+                        let mut current = map.get(&39).unwrap();
+
+                        while let Err(actual) = map.compare_exchange(&39, &current, *current + 1)
+                        {
+                            current = actual.unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads
+        {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(Some(8000), map.get(&39).map(|g| *g));
+    }
 }
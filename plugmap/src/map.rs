@@ -1,6 +1,14 @@
-use crate::{entry::EntryNode, table::Table};
+use crate::{
+    Entry,
+    iter::{Iter, KeyGuard, Keys, Nodes, Values},
+    table::{Change, Claim, Table},
+};
 use keep::*;
-use std::hash::{BuildHasher, Hash, RandomState};
+use std::{
+    borrow::Borrow,
+    hash::{BuildHasher, Hash, RandomState},
+    mem, ptr, thread,
+};
 
 
 pub struct PlugMap<Key, Val, S = RandomState>
@@ -31,24 +39,432 @@ where
     }
 
     /// Tries to remove an entry from the map.
-    pub fn remove(&self, key: &Key) -> Option<Guard<Val>>
+    ///
+    /// `key` may be any borrowed form of the map's key type, with `Hash` and `Eq` matching those of the key type.
+    pub fn remove<Q>(&self, key: &Q) -> Option<Guard<Val>>
     where
-        Val: std::fmt::Debug,
+        Key: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
     {
-        self.table.read().remove(key, self.hash(key))
+        let table = self.table.read();
+        let removed = table.remove(key, self.hash(key));
+        self.maintain(&table);
+
+        removed
     }
 
     /// Inserts a new key-value pair into the map or updates an existing one...
     pub fn insert(&self, key: Key, val: Val) -> Option<Guard<Val>>
     {
         let hash = self.hash(&key);
-        self.table.read().insert(EntryNode::new(key, val, hash))
+        let table = self.table.read();
+        let val = Keep::new(val);
+        let old = self.change_owned(&table, key, hash, |_, _| Change::Store(val.clone()));
+        self.maintain(&table);
+
+        match old
+        {
+            Ok(old) | Err(old) => old,
+        }
+    }
+
+    /// Inserts `val` only if the map holds no value for `key` and returns the inserted value.
+    ///
+    /// Fails with the existing value otherwise, in which case `val` is dropped.
+    pub fn try_insert(&self, key: Key, val: Val) -> Result<Guard<Val>, Guard<Val>>
+    {
+        let hash = self.hash(&key);
+        let table = self.table.read();
+        let mut val = Some(val);
+        let mut inserted: Option<Keep<Val>> = None;
+
+        let result = self.change_owned(&table, key, hash, |_, current| match current
+        {
+            Some(_) => Change::Unchanged,
+            None => Change::Store(
+                inserted
+                    .get_or_insert_with(|| Keep::new(val.take().expect("the value is only moved once")))
+                    .clone(),
+            ),
+        });
+
+        self.maintain(&table);
+
+        match result
+        {
+            Ok(_) => Ok(inserted.expect("a value has been inserted").read()),
+            Err(existing) => Err(existing.expect("the key is only left unchanged if it is present")),
+        }
+    }
+
+    /// Removes the entry of `key`, if `condition` holds for its value, and returns the removed value.
+    ///
+    /// If another thread changes the value while `condition` is running, `condition` is checked again for the new value.
+    pub fn remove_if<Q>(&self, key: &Q, condition: impl FnMut(&Val) -> bool) -> Option<Guard<Val>>
+    where
+        Key: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let table = self.table.read();
+        let removed = table.remove_if(key, self.hash(key), condition);
+        self.maintain(&table);
+
+        removed
+    }
+
+    /// Replaces the value of `key` with `new`, if the current value is the one guarded by `current`,
+    /// and returns the replaced value.
+    ///
+    /// Values are compared by identity, as with `Keep::exchange(..)`, not by equality or by address.
+    /// Fails with the actual value of `key`, or `None` if there is none, in which case `new` is dropped.
+    pub fn compare_exchange<Q>(
+        &self,
+        key: &Q,
+        current: &Guard<Val>,
+        new: Val,
+    ) -> Result<Guard<Val>, Option<Guard<Val>>>
+    where
+        Key: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let table = self.table.read();
+        let mut new = Some(new);
+        let mut stored: Option<Keep<Val>> = None;
+
+        let result = table.change(key, self.hash(key), None, |actual| match actual
+        {
+            Some(actual) if Guard::same_value(actual, current) => Change::Store(
+                stored
+                    .get_or_insert_with(|| Keep::new(new.take().expect("the value is only moved once")))
+                    .clone(),
+            ),
+
+            _ => Change::Unchanged,
+        });
+
+        self.help_resize(&table);
+
+        match result
+        {
+            Ok(old) => Ok(old.expect("only present values are replaced")),
+            Err(actual) => Err(actual),
+        }
+    }
+
+    /// Replaces the value of `key` with the result of `f` and returns the replaced value,
+    /// or `None` if the map holds no value for `key`.
+    ///
+    /// If another thread changes the value while `f` is running, `f` is called again with the new value,
+    /// so that no concurrent update is lost. The same happens for changes of other keys that share its bucket.
+    /// If the key is removed while `f` is running, its result is dropped and `None` is returned.
+    pub fn update<Q>(&self, key: &Q, mut f: impl FnMut(&Val) -> Val) -> Option<Guard<Val>>
+    where
+        Key: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let table = self.table.read();
+
+        let result = table.change(key, self.hash(key), None, |current| match current
+        {
+            Some(current) => Change::Store(Keep::new(f(current))),
+            None => Change::Unchanged,
+        });
+
+        self.help_resize(&table);
+
+        match result
+        {
+            Ok(old) | Err(old) => old,
+        }
+    }
+
+    /// Computes the new value of `key` from its current value and returns the replaced value.
+    ///
+    /// `f` is called with the current value, or `None` if there is none.
+    /// If it returns `None` the entry is removed, otherwise its result is inserted or replaces the current value.
+    /// If another thread changes the entry while `f` is running, `f` is called again with the new value.
+    pub fn compute(
+        &self,
+        key: Key,
+        mut f: impl FnMut(Option<&Val>) -> Option<Val>,
+    ) -> Option<Guard<Val>>
+    {
+        let hash = self.hash(&key);
+        let table = self.table.read();
+
+        let result = self.change_owned(&table, key, hash, |_, current| {
+            match (f(current.map(|val| &**val)), current)
+            {
+                (Some(val), _) => Change::Store(Keep::new(val)),
+                (None, Some(_)) => Change::Remove,
+                (None, None) => Change::Unchanged,
+            }
+        });
+
+        self.maintain(&table);
+        result.ok().flatten()
+    }
+
+    /// Returns the entry of `key`, to insert or modify its value in a single atomic step.
+    ///
+    /// The value to insert is only created by the first thread that finds the key vacant, see `Entry`.
+    pub fn entry(&self, key: Key) -> Entry<'_, Key, Val, S>
+    {
+        Entry::new(self, key)
     }
 
     /// Tries to get a value associated with `key`. Returns `None` if no such value exists.
-    pub fn get(&self, key: &Key) -> Option<Guard<Val>>
+    ///
+    /// `key` may be any borrowed form of the map's key type, with `Hash` and `Eq` matching those of the key type.
+    pub fn get<Q>(&self, key: &Q) -> Option<Guard<Val>>
+    where
+        Key: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let table = self.table.read();
+        let value = table.get(key, self.hash(key));
+        self.help_resize(&table);
+
+        value
+    }
+
+    /// Returns whether the map holds a value for `key`.
+    ///
+    /// `key` may be any borrowed form of the map's key type, with `Hash` and `Eq` matching those of the key type.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        Key: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let table = self.table.read();
+        let found = table.get(key, self.hash(key)).is_some();
+        self.help_resize(&table);
+
+        found
+    }
+
+    /// Returns an iterator over all entries of the map, guarding their keys and values.
+    ///
+    /// The iterator is weakly consistent: It never yields a key twice and yields every entry that
+    /// is present for the whole iteration, but entries inserted or removed while iterating
+    /// may or may not be yielded. Values are read when their entry is reached, not when the iteration starts.
+    /// Resizes do not disturb the iteration, entries that are moved to a new table are followed there.
+    pub fn iter(&self) -> Iter<Key, Val>
+    {
+        Iter(Nodes::new(self.table.clone()))
+    }
+
+    /// Returns an iterator over all keys of the map, see `PlugMap::iter()` for its consistency.
+    pub fn keys(&self) -> Keys<Key, Val>
+    {
+        Keys(Nodes::new(self.table.clone()))
+    }
+
+    /// Returns an iterator over all values of the map, see `PlugMap::iter()` for its consistency.
+    pub fn values(&self) -> Values<Key, Val>
+    {
+        Values(Nodes::new(self.table.clone()))
+    }
+
+    /// Returns the amount of entries in the map.
+    ///
+    /// While other threads modify the map, this is only a snapshot that might already be outdated.
+    pub fn len(&self) -> usize
     {
-        self.table.read().get(key, self.hash(key))
+        self.table.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.len() == 0
+    }
+
+    /// Returns the amount of buckets of the current table.
+    ///
+    /// The table grows automatically once it holds more than 3/4 of its capacity in entries.
+    pub fn capacity(&self) -> usize
+    {
+        self.table.read().capacity()
+    }
+
+    /// Returns the value of `key` after applying `modify` to it,
+    /// or inserts the value returned by `default` if there is none.
+    ///
+    /// A vacant key is claimed before `default` is called, so that it runs only once while the key is vacant.
+    /// Other threads upserting the key meanwhile wait until the value is stored, and then modify that one.
+    pub(crate) fn upsert(
+        &self,
+        key: Key,
+        default: impl FnOnce(&Key) -> Val,
+        mut modify: Option<impl FnMut(&Val) -> Val>,
+    ) -> Guard<Val>
+    {
+        let hash = self.hash(&key);
+        let table = self.table.read();
+
+        // Present keys are modified without moving the key into a keep
+        let current = match self.modify_present(&table, &key, hash, &mut modify)
+        {
+            Some(current) => current,
+            None => self.insert_claimed(&table, key, hash, default, modify),
+        };
+
+        self.maintain(&table);
+        current
+    }
+
+    /// Applies `modify` to the value of `key` and returns the new value, or the current one if there is no `modify`.
+    ///
+    /// Returns `None` if the key is vacant or claimed.
+    fn modify_present(
+        &self,
+        table: &Table<Key, Val>,
+        key: &Key,
+        hash: u64,
+        modify: &mut Option<impl FnMut(&Val) -> Val>,
+    ) -> Option<Guard<Val>>
+    {
+        let mut stored = None;
+
+        let result = table.change(key, hash, None, |current| match (current, &mut *modify)
+        {
+            (None, _) | (Some(_), None) => Change::Unchanged,
+            (Some(current), Some(modify)) =>
+            {
+                let val = Keep::new(modify(current));
+                stored = Some(val.clone());
+                Change::Store(val)
+            }
+        });
+
+        match result
+        {
+            Ok(_) => stored.map(|val| val.read()),
+            Err(current) => current,
+        }
+    }
+
+    /// Claims the vacant `key` and inserts the value returned by `default`, see `PlugMap::upsert(..)`.
+    ///
+    /// If another thread claimed the key, this waits until its value is stored.
+    fn insert_claimed(
+        &self,
+        table: &Table<Key, Val>,
+        key: Key,
+        hash: u64,
+        default: impl FnOnce(&Key) -> Val,
+        mut modify: Option<impl FnMut(&Val) -> Val>,
+    ) -> Guard<Val>
+    {
+        let vacant = Keep::new(key);
+        let key = vacant.read();
+
+        loop
+        {
+            match table.claim(hash, &vacant)
+            {
+                Claim::Claimed => break,
+                Claim::Present(current) if modify.is_none() => return current,
+                Claim::Present(_) => {}
+                Claim::Pending => thread::yield_now(),
+            }
+
+            if let Some(current) = self.modify_present(table, &key, hash, &mut modify)
+            {
+                return current;
+            }
+        }
+
+        // Removes the claim if `default` panics, so that other threads do not wait for it forever
+        let claim = Unclaim {
+            table,
+            hash,
+            vacant: &vacant,
+        };
+
+        let val = Keep::new(default(&key));
+        let mut stored = None;
+
+        // Fills the claim, unless another thread inserted the key in the meantime
+        let result = table.change(&*key, hash, Some(&vacant), |current| {
+            let val = match (current, &mut modify)
+            {
+                (None, _) => val.clone(),
+                (Some(_), None) => return Change::Unchanged,
+                (Some(current), Some(modify)) => Keep::new(modify(current)),
+            };
+
+            stored = Some(val.clone());
+            Change::Store(val)
+        });
+
+        mem::forget(claim);
+
+        match result
+        {
+            Ok(_) => stored.expect("a value has been stored").read(),
+            Err(current) => current.expect("present keys are the only ones left unchanged"),
+        }
+    }
+
+    /// Changes the value of `key` like `Table::change(..)`, but `f` is called with the key as well.
+    ///
+    /// The key is only moved into a keep once it turns out to be vacant, so that present keys do not allocate one.
+    fn change_owned(
+        &self,
+        table: &Table<Key, Val>,
+        key: Key,
+        hash: u64,
+        mut f: impl FnMut(&Key, Option<&Guard<Val>>) -> Change<Val>,
+    ) -> Result<Option<Guard<Val>>, Option<Guard<Val>>>
+    {
+        let present = table.change(&key, hash, None, |current| match current
+        {
+            Some(_) => f(&key, current),
+            None => Change::Unchanged,
+        });
+
+        // Only vacant keys are left unchanged without calling `f`
+        if !matches!(present, Err(None))
+        {
+            return present;
+        }
+
+        let vacant = Keep::new(key);
+        let key = vacant.read();
+        table.change(&*key, hash, Some(&vacant), |current| f(&key, current))
+    }
+
+    /// Grows the table once it is overloaded, every writer helps moving a few entries into the new table.
+    fn maintain(&self, table: &Guard<Table<Key, Val>>)
+    {
+        if table.is_overloaded()
+        {
+            table.start_resize();
+        }
+
+        self.help_resize(table);
+    }
+
+    /// Moves a few entries into the next table, if `table` is being resized.
+    ///
+    /// Readers help as well, so that a resize also finishes while nobody writes to the map.
+    /// Once every entry has been moved, the next table replaces `table`.
+    fn help_resize(&self, table: &Guard<Table<Key, Val>>)
+    {
+        let Some(next) = table.help_resize()
+        else
+        {
+            return;
+        };
+
+        // Only replace the table if it still is the one that was resized
+        let marker = self.table.mark();
+
+        if ptr::eq(&*self.table.read(), &**table)
+        {
+            let _ = self.table.exchange_with(marker, &next);
+        }
     }
 
     #[inline]
@@ -79,3 +495,36 @@ where
         Self::new()
     }
 }
+
+
+impl<Key, Val, S> IntoIterator for &PlugMap<Key, Val, S>
+where
+    Key: Hash + Eq,
+    S: BuildHasher,
+{
+    type Item = (KeyGuard<Key, Val>, Guard<Val>);
+    type IntoIter = Iter<Key, Val>;
+
+    fn into_iter(self) -> Iter<Key, Val>
+    {
+        self.iter()
+    }
+}
+
+
+/// Removes the claim made with `vacant` when dropped, unless it has been filled.
+struct Unclaim<'a, Key: Eq, Val>
+{
+    table: &'a Table<Key, Val>,
+    hash: u64,
+    vacant: &'a Keep<Key>,
+}
+
+
+impl<Key: Eq, Val> Drop for Unclaim<'_, Key, Val>
+{
+    fn drop(&mut self)
+    {
+        self.table.unclaim(self.hash, self.vacant);
+    }
+}
@@ -0,0 +1,98 @@
+use crate::PlugMap;
+use keep::*;
+use std::hash::{BuildHasher, Hash};
+
+
+/// The entry of a single key in a `PlugMap`, created by `PlugMap::entry(..)`.
+///
+/// Unlike the entries of `std::collections::HashMap`, this entry does not know whether its key is
+/// occupied until it is consumed, as other threads can insert or remove the key at any time.
+/// Consuming an entry decides between inserting and modifying in a single atomic step per key.
+/// A vacant key is claimed before its value is created, so that other threads consuming entries
+/// of the same key wait for that value instead of creating their own, see `Entry::or_insert_with(..)`.
+pub struct Entry<'a, Key, Val, S, F = fn(&Val) -> Val>
+{
+    map: &'a PlugMap<Key, Val, S>,
+    key: Key,
+    modify: Option<F>,
+}
+
+
+impl<'a, Key, Val, S> Entry<'a, Key, Val, S>
+{
+    pub(crate) fn new(map: &'a PlugMap<Key, Val, S>, key: Key) -> Self
+    {
+        Self {
+            map,
+            key,
+            modify: None,
+        }
+    }
+}
+
+
+impl<'a, Key, Val, S, F> Entry<'a, Key, Val, S, F>
+where
+    Key: Hash + Eq,
+    S: BuildHasher,
+    F: FnMut(&Val) -> Val,
+{
+    #[inline]
+    pub fn key(&self) -> &Key
+    {
+        &self.key
+    }
+
+    /// Replaces the value with the result of `f`, if the key is occupied when this entry is consumed.
+    ///
+    /// `f` is called again with the new value, if another thread changed the value while `f` was running.
+    pub fn and_modify(
+        self,
+        mut f: impl FnMut(&Val) -> Val,
+    ) -> Entry<'a, Key, Val, S, impl FnMut(&Val) -> Val>
+    {
+        let mut modify = self.modify;
+
+        Entry {
+            map: self.map,
+            key: self.key,
+            modify: Some(move |val: &Val| match &mut modify
+            {
+                Some(modify) => f(&modify(val)),
+                None => f(val),
+            }),
+        }
+    }
+
+    /// Inserts `default` if the key is vacant and returns the current value.
+    pub fn or_insert(self, default: Val) -> Guard<Val>
+    {
+        self.or_insert_with(|| default)
+    }
+
+    /// Inserts the result of `default` if the key is vacant and returns the current value.
+    ///
+    /// `default` is only called by the thread that claims the vacant key, even if several threads insert it at once.
+    /// The others wait until its value is inserted, so `default` must not insert the same key itself.
+    /// Readers do not wait, they find the key vacant until then.
+    pub fn or_insert_with(self, default: impl FnOnce() -> Val) -> Guard<Val>
+    {
+        self.or_insert_with_key(|_| default())
+    }
+
+    /// Same as `Entry::or_insert_with(..)`, but `default` is called with the key of this entry.
+    ///
+    /// Like there, `default` is only called by the thread that claims the vacant key.
+    pub fn or_insert_with_key(self, default: impl FnOnce(&Key) -> Val) -> Guard<Val>
+    {
+        self.map.upsert(self.key, default, self.modify)
+    }
+
+    /// Inserts the default value if the key is vacant and returns the current value.
+    pub fn or_default(self) -> Guard<Val>
+    where
+        Val: Default,
+    {
+        self.or_insert_with(Val::default)
+    }
+}
@@ -1,14 +1,69 @@
-use crate::entry::{Entry, EntryNode};
+use crate::{
+    counter::StripedCounter,
+    entry::{Entry, EntryNode},
+};
 use keep::*;
-use std::sync::atomic::AtomicUsize;
+use std::{
+    borrow::Borrow,
+    ptr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+};
+
+
+/// How `Table::change(..)` changes the value of a key.
+pub enum Change<Val>
+{
+    /// Leaves the entry as it is.
+    Unchanged,
+
+    /// Stores a new value, replacing the current one or inserting the key if it is vacant.
+    Store(Keep<Val>),
+
+    /// Removes the key, if it is present.
+    Remove,
+}
+
+
+/// The result of claiming a vacant key with `Table::claim(..)`.
+pub enum Claim<Val>
+{
+    /// The key has been claimed by the caller.
+    Claimed,
+
+    /// The key is present with this value.
+    Present(Guard<Val>),
+
+    /// Another thread claimed the key and has not stored its value yet.
+    Pending,
+}
 
 
 pub struct Table<Key, Val>
 {
     size: usize,
     capacity: usize,
-    entry_count: AtomicUsize,
+
+    /// The amount of entries in this table and the tables it is being migrated into, which all share it.
+    ///
+    /// Moving entries into the next table does not change it, so they are never counted twice.
+    /// Signed, as a thread can remove entries that other threads counted.
+    entry_count: Arc<StripedCounter>,
     entries: Box<[Keep<Entry<Key, Val>>]>,
+
+    /// The table this table is being migrated into.
+    next: Keep<Option<Keep<Table<Key, Val>>>>,
+
+    /// Whether `next` has been set, so that readers can check for a resize without reading it.
+    resizing: AtomicBool,
+
+    /// The index of the next entry which is not yet claimed for migration.
+    migration_index: AtomicUsize,
+
+    /// The amount of entries that have been moved into the next table.
+    moved_count: AtomicUsize,
 }
 
 
@@ -16,7 +71,19 @@ impl<Key, Val> Table<Key, Val>
 where
     Key: Eq,
 {
+    /// The maximum ratio of entries per bucket, before the table should grow.
+    const LOAD_FACTOR: (usize, usize) = (3, 4);
+
+    /// The amount of entries a thread migrates at once when helping with a resize.
+    const MIGRATION_STRIDE: usize = 16;
+
     pub fn new(size: usize) -> Self
+    {
+        Self::with_entry_count(size, Arc::new(StripedCounter::new()))
+    }
+
+    /// Creates a table that counts its entries in `entry_count`, which is shared with the table it is migrated from.
+    fn with_entry_count(size: usize, entry_count: Arc<StripedCounter>) -> Self
     {
         let mut entries = Box::new_uninit_slice(1 << size);
 
@@ -28,156 +95,388 @@ where
         Self {
             size,
             capacity: 1 << size,
-            entry_count: AtomicUsize::new(0),
+            entry_count,
             entries: unsafe { entries.assume_init() },
+            next: Keep::new(None),
+            resizing: AtomicBool::new(false),
+            migration_index: AtomicUsize::new(0),
+            moved_count: AtomicUsize::new(0),
         }
     }
 
-    pub fn remove(&self, key: &Key, hash: u64) -> Option<Guard<Val>>
+    pub fn remove<Q>(&self, key: &Q, hash: u64) -> Option<Guard<Val>>
     where
-        Val: std::fmt::Debug,
+        Key: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.remove_if(key, hash, |_| true)
+    }
+
+    /// Removes the node holding `key`, if `condition` holds for its value.
+    ///
+    /// `condition` is checked for the value in the entry that is exchanged to remove the node, so the node is only
+    /// removed if its value did not change in the meantime, otherwise `condition` is checked again for the new value.
+    pub fn remove_if<Q>(
+        &self,
+        key: &Q,
+        hash: u64,
+        mut condition: impl FnMut(&Val) -> bool,
+    ) -> Option<Guard<Val>>
+    where
+        Key: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let removed = self.change(key, hash, None, |current| match current
+        {
+            Some(current) if condition(current) => Change::Remove,
+            _ => Change::Unchanged,
+        });
+
+        removed.ok().flatten()
+    }
+
+    /// Returns the value of `key`, if there is any.
+    ///
+    /// Frozen entries are moved into the next table first, so that lookups do not miss changes made there.
+    pub fn get<Q>(&self, key: &Q, hash: u64) -> Option<Guard<Val>>
+    where
+        Key: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let entry = self.entry_of(hash).read();
+
+        match &*entry
+        {
+            Entry::Frozen(_) | Entry::Moved => self.forward(hash, |next| next.get(key, hash)),
+            _ => entry.search(key),
+        }
+    }
+
+    /// Changes the value of `key` as decided by `f`, in a single exchange of its entry.
+    ///
+    /// `f` is called with the current value, or `None` if the key is vacant or claimed. If another thread changes
+    /// the entry before the change is stored, `f` is called again. Vacant keys can only be inserted
+    /// with `vacant`, a keep of the key.
+    ///
+    /// Returns the replaced or removed value if the entry changed, or the current value if it did not.
+    pub fn change<Q>(
+        &self,
+        key: &Q,
+        hash: u64,
+        vacant: Option<&Keep<Key>>,
+        mut f: impl FnMut(Option<&Guard<Val>>) -> Change<Val>,
+    ) -> Result<Option<Guard<Val>>, Option<Guard<Val>>>
+    where
+        Key: Borrow<Q>,
+        Q: ?Sized + Eq,
     {
         let entry = self.entry_of(hash);
 
-        'outer: loop
+        loop
         {
             let entry_guard = entry.read();
-            let entry_marker = entry.mark();
 
-            // Examine the entry:
-            match &*entry_guard
+            let nodes = match &*entry_guard
             {
-                // If the entry is empty return none
-                Entry::Empty => break 'outer None,
+                Entry::Empty => &[][..],
+                Entry::Head(nodes) => nodes,
+                Entry::Frozen(_) | Entry::Moved =>
+                {
+                    break self.forward(hash, |next| next.change(key, hash, vacant, f));
+                }
+            };
 
-                // If however the entry is not empty its node and its children will have to be searched for key:
-                Entry::Head(keep) =>
+            // Claimed keys are vacant until a value is stored for them, which fills their claim
+            let position = entry_guard.position(key);
+            let current = position.and_then(|index| nodes[index].value());
+
+            let (new, count): (Box<[_]>, _) = match (f(current.as_ref()), position)
+            {
+                (Change::Unchanged, _) => break Err(current),
+                (Change::Remove, _) if current.is_none() => break Err(current),
+
+                (Change::Store(val), Some(index)) =>
                 {
-                    let next = keep.read().next().read();
-                    let guard = keep.read().value();
-
-                    // Check whether or not the head has a next node:
-                    match &*next
-                    {
-                        // If this head does have a next node it (the head) must first be checked for a matching key.
-                        // If the key matches the head needs to be replaced by its next child node,
-                        // if it does not have a next child node it needs to be replaced by a Entry::Empty.
-                        //
-                        // If the head does not match but has children, the list of children must be searched for a matching key,
-                        // if a matching key is found that child is replaced by its own direct child.
-                        Some(next) =>
-                        {
-                            if keep.read().key() == key
-                            {
-                                if entry
-                                    .exchange_with(
-                                        entry_marker,
-                                        &Keep::new(Entry::Head(next.clone())),
-                                    )
-                                    .is_err()
-                                {
-                                    continue 'outer;
-                                }
-
-                                break 'outer Some(guard);
-                            }
-
-                            let mut current = keep.read().next().clone();
-
-                            'inner: loop
-                            {
-                                let marker = current.read();
-
-                                match &*current.read()
-                                {
-                                    Some(node) =>
-                                    {
-                                        let next_child = node.read().next().clone();
-
-                                        if node.read().key() == key
-                                        {
-                                            let guard = node.read().value();
-
-                                            if current
-                                                .exchange(
-                                                    &marker,
-                                                    next_child.read().as_ref().clone(),
-                                                )
-                                                .is_err()
-                                            {
-                                                current = keep.read().next().clone();
-                                                continue 'inner;
-                                            }
-
-                                            break 'outer Some(guard);
-                                        }
-
-                                        current = next_child;
-                                    }
-
-                                    None => break 'outer None,
-                                }
-                            }
-                        }
-
-                        // If the head does not have a next node, its key can simply be compared to key,
-                        // if the keys match this Entry::Head(..) can simply be swapped for a Entry::Empty.
-                        None =>
+                    let new = nodes
+                        .iter()
+                        .enumerate()
+                        .map(|(i, node)| match i == index
                         {
-                            if keep.read().key() != key
-                            {
-                                break 'outer None;
-                            }
-
-                            let empty_entry = Keep::new(Entry::Empty);
-                            if entry.exchange_with(entry_marker, &empty_entry).is_err()
-                            {
-                                continue;
-                            }
-
-                            break 'outer Some(guard);
-                        }
-                    }
+                            true => node.with_value(val.clone()),
+                            false => node.clone(),
+                        })
+                        .collect();
+
+                    (new, current.is_none() as isize)
+                }
+
+                (Change::Store(val), None) =>
+                {
+                    let key = vacant.expect("vacant keys are only inserted with a keep of the key");
+                    let new = nodes
+                        .iter()
+                        .cloned()
+                        .chain([EntryNode::new(key.clone(), val, hash)])
+                        .collect();
+
+                    (new, 1)
+                }
+
+                (Change::Remove, position) =>
+                {
+                    let index = position.expect("present keys have a node");
+                    let new = Self::without_node(nodes, index);
+
+                    (new, -1)
                 }
+            };
+
+            let new = match new.is_empty()
+            {
+                true => Entry::Empty,
+                false => Entry::Head(new),
+            };
+
+            if entry.exchange(&entry_guard, new).is_err()
+            {
+                continue;
             }
+
+            self.entry_count.add(count);
+            break Ok(current);
         }
     }
 
-    pub fn get(&self, key: &Key, hash: u64) -> Option<Guard<Val>>
+    /// Claims the vacant `key` with a node without a value, so that other threads wait until the caller stores one.
+    ///
+    /// The claim is made with `vacant`, a keep of the key, and filled by storing a value with `Table::change(..)`.
+    pub fn claim(&self, hash: u64, vacant: &Keep<Key>) -> Claim<Val>
     {
-        self.entry_of(hash).read().search(key)
+        let entry = self.entry_of(hash);
+        let key = vacant.read();
+
+        loop
+        {
+            let entry_guard = entry.read();
+
+            let nodes = match &*entry_guard
+            {
+                Entry::Empty => &[][..],
+                Entry::Head(nodes) => nodes,
+                Entry::Frozen(_) | Entry::Moved => break self.forward(hash, |next| next.claim(hash, vacant)),
+            };
+
+            if let Some(index) = entry_guard.position(&*key)
+            {
+                break match nodes[index].value()
+                {
+                    Some(current) => Claim::Present(current),
+                    None => Claim::Pending,
+                };
+            }
+
+            let new = nodes
+                .iter()
+                .cloned()
+                .chain([EntryNode::claim(vacant.clone(), hash)])
+                .collect();
+
+            if entry.exchange(&entry_guard, Entry::Head(new)).is_ok()
+            {
+                break Claim::Claimed;
+            }
+        }
     }
 
-    pub fn insert(&self, entry_node: EntryNode<Key, Val>) -> Option<Guard<Val>>
+    /// Removes the claim made with `vacant`, if no value has been stored for it.
+    pub fn unclaim(&self, hash: u64, vacant: &Keep<Key>)
     {
-        let hash = entry_node.hash();
-        let entry_node = Keep::new(entry_node);
         let entry = self.entry_of(hash);
 
         loop
         {
             let entry_guard = entry.read();
-            let entry_marker = entry.mark();
 
-            match &*entry_guard
+            let nodes = match &*entry_guard
+            {
+                Entry::Empty => &[][..],
+                Entry::Head(nodes) => nodes,
+                Entry::Frozen(_) | Entry::Moved => break self.forward(hash, |next| next.unclaim(hash, vacant)),
+            };
+
+            let Some(index) = nodes.iter().position(|node| node.is_claim_of(vacant))
+            else
+            {
+                break;
+            };
+
+            let new = Self::without_node(nodes, index);
+            let new = match new.is_empty()
+            {
+                true => Entry::Empty,
+                false => Entry::Head(new),
+            };
+
+            if entry.exchange(&entry_guard, new).is_ok()
             {
-                Entry::Empty =>
+                break;
+            }
+        }
+    }
+
+    /// Returns a copy of `nodes` without the node at `index`.
+    fn without_node(nodes: &[EntryNode<Key, Val>], index: usize) -> Box<[EntryNode<Key, Val>]>
+    {
+        nodes
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != index)
+            .map(|(_, node)| node.clone())
+            .collect()
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize
+    {
+        self.capacity
+    }
+
+    /// Returns the amount of entries in this table and the tables it is being migrated into.
+    pub fn len(&self) -> usize
+    {
+        self.entry_count.sum().max(0) as usize
+    }
+
+    /// Returns whether this table has grown beyond its load factor and should be resized.
+    pub fn is_overloaded(&self) -> bool
+    {
+        let (numerator, denominator) = Self::LOAD_FACTOR;
+        let limit = (self.capacity * numerator / denominator) as isize;
+
+        // Only sum up every stripe, once the stripe of this thread holds more than its share of the limit
+        let stripes = self.entry_count.stripes() as isize;
+        self.entry_count.local() * stripes > limit && self.entry_count.sum() > limit
+    }
+
+    /// Starts migrating this table into a table of twice its capacity, unless a migration already started.
+    pub fn start_resize(&self)
+    {
+        let next = self.next.read();
+
+        if next.is_none()
+            && self
+                .next
+                .exchange(&next, Some(Keep::new(Table::with_entry_count(self.size + 1, self.entry_count.clone()))))
+                .is_ok()
+        {
+            self.resizing.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Migrates a few entries into the next table if this table is being resized.
+    ///
+    /// Returns the next table once every entry of this table has been moved.
+    pub fn help_resize(&self) -> Option<Keep<Table<Key, Val>>>
+    {
+        if !self.resizing.load(Ordering::SeqCst)
+        {
+            return None;
+        }
+
+        let next = self.next_table()?;
+        let start = self
+            .migration_index
+            .fetch_add(Self::MIGRATION_STRIDE, Ordering::SeqCst);
+
+        for index in start..self.capacity.min(start + Self::MIGRATION_STRIDE)
+        {
+            self.migrate(index, &next.read());
+        }
+
+        (self.moved_count.load(Ordering::SeqCst) == self.capacity).then_some(next)
+    }
+
+    /// Runs `f` on the next table, after making sure the entry for `hash` has been moved into it.
+    fn forward<R>(&self, hash: u64, f: impl FnOnce(&Table<Key, Val>) -> R) -> R
+    {
+        let next = self
+            .next_table()
+            .expect("entries are only frozen after the next table has been set")
+            .read();
+
+        self.migrate(self.index_of(hash), &next);
+        f(&next)
+    }
+
+    /// Moves the entry at `index` into `next`.
+    ///
+    /// This can be called by any amount of threads at once, only one of them will complete the move.
+    fn migrate(&self, index: usize, next: &Table<Key, Val>)
+    {
+        let entry = self.entry_at(index);
+
+        loop
+        {
+            let entry_guard = entry.read();
+
+            // Freeze the entry first, so that nodes cannot be added or removed while they are moved.
+            let nodes = match &*entry_guard
+            {
+                Entry::Moved => return,
+                Entry::Frozen(nodes) => nodes,
+                Entry::Empty | Entry::Head(_) =>
                 {
-                    let new = Keep::new(Entry::Head(entry_node.clone()));
+                    let nodes = entry_guard.nodes().unwrap_or_default().into();
+                    let _ = entry.exchange(&entry_guard, Entry::Frozen(nodes));
+                    continue;
+                }
+            };
 
-                    if entry.exchange_with(entry_marker, &new).is_err()
-                    {
-                        continue;
-                    }
+            // Every node of this entry ends up in one of two entries of the next table,
+            // which are not used by anyone else until this entry has been moved.
+            for next_index in [index, index + self.capacity]
+            {
+                let next_entry = next.entry_at(next_index);
+                let next_guard = next_entry.read();
 
-                    break None;
+                // If this entry is not frozen anymore, another thread completed the move
+                // and the next entry might already be used.
+                if !ptr::eq(&*entry.read(), &*entry_guard)
+                {
+                    return;
                 }
 
-                Entry::Head(keep) => break keep.read().update(&entry_node),
+                let moved: Box<[_]> = nodes
+                    .iter()
+                    .filter(|node| next.index_of(node.hash()) == next_index)
+                    .cloned()
+                    .collect();
+
+                let new = match moved.is_empty()
+                {
+                    true => Entry::Empty,
+                    false => Entry::Head(moved),
+                };
+
+                // If this fails, another thread already moved the same nodes in
+                let _ = next_entry.exchange(&next_guard, new);
             }
+
+            if entry.exchange(&entry_guard, Entry::Moved).is_ok()
+            {
+                self.moved_count.fetch_add(1, Ordering::SeqCst);
+            }
+
+            return;
         }
     }
 
+    #[inline]
+    pub fn next_table(&self) -> Option<Keep<Table<Key, Val>>>
+    {
+        (*self.next.read()).clone()
+    }
+
     #[inline]
     fn index_of(&self, hash: u64) -> usize
     {
@@ -185,7 +484,7 @@ where
     }
 
     #[inline]
-    fn entry_at(&self, index: usize) -> &Keep<Entry<Key, Val>>
+    pub fn entry_at(&self, index: usize) -> &Keep<Entry<Key, Val>>
     {
         &self.entries[index]
     }